@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{mirror::Mirror, Config};
+
+/// Download FASTQ files of SRA, ENA, DDBJ, GEO and CNCB GSA accessions.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file, default values are used when it does not exist.
+    #[arg(long, global = true, default_value = "config.json")]
+    pub config: PathBuf,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    /// Defaults to `download`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download every line of the list.
+    Download(ListArgs),
+    /// Look up every line of the list and what it holds, without downloading.
+    Preflight(ListArgs),
+    /// Print the runs every line of the list resolves to, with the names they are saved under.
+    Resolve {
+        #[command(flatten)]
+        list: ListArgs,
+        /// Write the runs as tab separated values to this file instead of the standard output,
+        /// which otherwise holds only the runs while progress goes to the standard error.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Validate downloaded `.fastq.gz` files and count their reads.
+    Verify {
+        /// Directory searched for `.fastq.gz` files when no file is given.
+        #[arg(long, default_value = ".")]
+        out_dir: PathBuf,
+        files: Vec<PathBuf>,
+    },
+    /// Check the config and the list without touching the network.
+    Check(ListArgs),
+}
+impl Default for Command {
+    fn default() -> Self {
+        Self::Download(ListArgs::default())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Download list, one accession per line.
+    #[arg(long, default_value = "download_list.txt")]
+    pub list: PathBuf,
+    /// Directory files are saved to, directory lines of the list are taken relative to it.
+    #[arg(long, default_value = ".")]
+    pub out_dir: PathBuf,
+}
+impl Default for ListArgs {
+    fn default() -> Self {
+        Self {
+            list: "download_list.txt".into(),
+            out_dir: ".".into(),
+        }
+    }
+}
+
+/// Flags taking precedence over the config file.
+#[derive(Debug, Args)]
+pub struct ConfigOverrides {
+    #[arg(long, global = true)]
+    pub retry_times: Option<usize>,
+    #[arg(long, global = true)]
+    pub read_meta_timeout: Option<usize>,
+    #[arg(long, global = true)]
+    pub max_concurrent_requests: Option<usize>,
+    #[arg(long, global = true)]
+    pub download_timeout: Option<usize>,
+    #[arg(long, global = true)]
+    pub segments: Option<usize>,
+    #[arg(long, global = true)]
+    pub min_segment_size: Option<u64>,
+    #[arg(long, global = true)]
+    pub ncbi_api_key: Option<String>,
+    /// Mirrors to try in order, e.g. `ena,ncbi`.
+    #[arg(long, global = true, value_delimiter = ',', value_parser = parse_mirror)]
+    pub mirrors: Option<Vec<Mirror>>,
+    #[arg(long, global = true)]
+    pub validate_fastq: bool,
+    #[arg(long, global = true)]
+    pub verify_read_counts: bool,
+}
+impl ConfigOverrides {
+    pub fn apply(self, config: &mut Config) {
+        if let Some(retry_times) = self.retry_times {
+            config.retry_times = retry_times;
+        }
+        if let Some(read_meta_timeout) = self.read_meta_timeout {
+            config.read_meta_timeout = read_meta_timeout;
+        }
+        if let Some(max_concurrent_requests) = self.max_concurrent_requests {
+            config.max_concurrent_requests = max_concurrent_requests;
+        }
+        if let Some(download_timeout) = self.download_timeout {
+            config.download_timeout = download_timeout;
+        }
+        if let Some(segments) = self.segments {
+            config.segments = segments;
+        }
+        if let Some(min_segment_size) = self.min_segment_size {
+            config.min_segment_size = min_segment_size;
+        }
+        if let Some(ncbi_api_key) = self.ncbi_api_key {
+            config.ncbi_api_key = Some(ncbi_api_key);
+        }
+        if let Some(mirrors) = self.mirrors {
+            config.mirrors = mirrors;
+        }
+        if self.validate_fastq {
+            config.validate_fastq = true;
+        }
+        if self.verify_read_counts {
+            config.verify_read_counts = true;
+        }
+    }
+}
+
+fn parse_mirror(mirror: &str) -> Result<Mirror, String> {
+    serde_json::from_value(serde_json::Value::String(mirror.to_owned()))
+        .map_err(|_| format!("unknown mirror {}, expected ncbi, ena or ddbj", mirror))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let cli = Cli::parse_from([
+            "fastq-downloader",
+            "download",
+            "--list",
+            "batch.txt",
+            "--segments",
+            "4",
+            "--mirrors",
+            "ena,ncbi",
+            "--verify-read-counts",
+        ]);
+        let mut config = Config::default();
+        cli.overrides.apply(&mut config);
+        assert_eq!(config.segments, 4);
+        assert_eq!(config.mirrors, vec![Mirror::Ena, Mirror::Ncbi]);
+        assert!(config.verify_read_counts);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Download(ListArgs { list, .. })) if list.as_path() == std::path::Path::new("batch.txt")
+        ));
+    }
+}
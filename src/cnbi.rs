@@ -1,99 +1,540 @@
+use regex::Regex;
 use reqwest::Url;
-use std::{str::FromStr, sync::Arc};
+use scraper::{ElementRef, Html, Selector};
+use std::{collections::HashMap, sync::Arc};
 
-use crate::{Config, NetworkInstance};
+use crate::{ncbi::ReadMetaError, Config, NetworkInstance, TaskError};
 
-pub async fn read_alias_and_crr_with_crx(
+const GSA_BROWSE: &str = "https://ngdc.cncb.ac.cn/gsa/browse";
+const BIOPROJECT_BROWSE: &str = "https://ngdc.cncb.ac.cn/bioproject/browse";
+const BIOSAMPLE_BROWSE: &str = "https://ngdc.cncb.ac.cn/biosample/browse";
+const GSA_DOWNLOAD_HOST: &str = "download.cncb.ac.cn";
+/// Accessions of GSA-Human, which only hands out data on approved requests.
+pub const CONTROLLED_PREFIXES: [&str; 3] = ["HRA", "HRX", "HRR"];
+
+/// Whether the accession belongs to GSA-Human and cannot be downloaded anonymously.
+pub fn is_controlled_accession(accession: &str) -> bool {
+    CONTROLLED_PREFIXES
+        .iter()
+        .any(|prefix| accession.starts_with(prefix))
+}
+
+/// A run on the Genome Sequence Archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GsaRun {
+    pub crr: String,
+    pub alias: String,
+    pub files: Vec<GsaFile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GsaFile {
+    pub name: String,
+    pub url: Url,
+    /// Lowercase MD5 published by GSA, if any.
+    pub md5: Option<String>,
+}
+
+/// Read the accessions of the runs listed on a CRX page, in page order.
+pub fn parse_crx_page(document: &str, crx: &str) -> Result<Vec<String>, ReadMetaError> {
+    let html = Html::parse_document(document);
+    let links = Selector::parse("a[href]").expect("selector to be valid");
+    let crr_regex = Regex::new(r"browse/CRA\d+/(CRR\d+)").expect("regex to be valid");
+    let mut crrs = Vec::new();
+    for link in html.select(&links) {
+        let href = link.value().attr("href").unwrap_or_default();
+        if let Some(captures) = crr_regex.captures(href) {
+            let crr = captures[1].to_owned();
+            if !crrs.contains(&crr) {
+                crrs.push(crr);
+            }
+        }
+    }
+    if crrs.is_empty() {
+        return Err(ReadMetaError::NotFound(crx.to_owned()));
+    }
+    Ok(crrs)
+}
+
+/// Read the experiments linked from a CRA, PRJCA or SAMC page as `(CRA, CRX)`, in page order.
+/// Links to a whole CRA without an experiment are returned with an empty CRX.
+pub fn parse_experiment_links(document: &str) -> Vec<(String, String)> {
+    let html = Html::parse_document(document);
+    let links = Selector::parse("a[href]").expect("selector to be valid");
+    let experiment_regex =
+        Regex::new(r"browse/(CRA\d+)(?:/(CRX\d+))?/?$").expect("regex to be valid");
+    let mut experiments = Vec::new();
+    for link in html.select(&links) {
+        let href = link.value().attr("href").unwrap_or_default();
+        if let Some(captures) = experiment_regex.captures(href) {
+            let experiment = (
+                captures[1].to_owned(),
+                captures
+                    .get(2)
+                    .map(|crx| crx.as_str().to_owned())
+                    .unwrap_or_default(),
+            );
+            if !experiments.contains(&experiment) {
+                experiments.push(experiment);
+            }
+        }
+    }
+    experiments
+}
+
+/// Read the GSA-Human accessions linked from a PRJCA or SAMC page, in page order.
+pub fn parse_controlled_links(document: &str) -> Vec<String> {
+    let html = Html::parse_document(document);
+    let links = Selector::parse("a[href]").expect("selector to be valid");
+    let hra_regex = Regex::new(r"gsa-human/browse/(HRA\d+)").expect("regex to be valid");
+    let mut hras = Vec::new();
+    for link in html.select(&links) {
+        let href = link.value().attr("href").unwrap_or_default();
+        if let Some(captures) = hra_regex.captures(href) {
+            let hra = captures[1].to_owned();
+            if !hras.contains(&hra) {
+                hras.push(hra);
+            }
+        }
+    }
+    hras
+}
+
+/// Read the alias and the files of a run from its CRR page.
+/// The alias is the cell following the one holding the run accession.
+pub fn parse_crr_page(document: &str, crr: &str) -> Result<GsaRun, ReadMetaError> {
+    let html = Html::parse_document(document);
+    let cells = Selector::parse("td").expect("selector to be valid");
+    let links = Selector::parse("a[href]").expect("selector to be valid");
+    let alias = html
+        .select(&cells)
+        .find(|cell| text_of(cell) == crr)
+        .and_then(|cell| {
+            cell.next_siblings()
+                .filter_map(ElementRef::wrap)
+                .find(|sibling| sibling.value().name() == "td")
+        })
+        .map(|cell| text_of(&cell))
+        .filter(|alias| !alias.is_empty())
+        .ok_or(ReadMetaError::MissingField {
+            accession: crr.to_owned(),
+            field: "alias",
+        })?;
+    let mut files = Vec::new();
+    for link in html.select(&links) {
+        let href = link.value().attr("href").unwrap_or_default();
+        let Some(position) = href.find(GSA_DOWNLOAD_HOST) else {
+            continue;
+        };
+        // Links may come with any scheme or none at all, always download over https.
+        let url = Url::parse(&format!("https://{}", &href[position..]))
+            .map_err(|e| ReadMetaError::Malformed(format!("file link {}: {}", href, e)))?;
+        let in_run = url
+            .path_segments()
+            .is_some_and(|mut segments| segments.any(|segment| segment == crr));
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default()
+            .to_owned();
+        if !in_run || name.is_empty() || files.iter().any(|file: &GsaFile| file.url == url) {
+            continue;
+        }
+        // The MD5 sits in the same table row as the link.
+        let md5 = link
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .find(|ancestor| ancestor.value().name() == "tr")
+            .and_then(|row| {
+                row.select(&cells)
+                    .map(|cell| text_of(&cell))
+                    .find(|text| is_md5(text))
+            })
+            .map(|md5| md5.to_ascii_lowercase());
+        files.push(GsaFile { name, url, md5 });
+    }
+    if files.is_empty() {
+        return Err(ReadMetaError::MissingField {
+            accession: crr.to_owned(),
+            field: "file list",
+        });
+    }
+    Ok(GsaRun {
+        crr: crr.to_owned(),
+        alias,
+        files,
+    })
+}
+
+/// Link to the `md5sum.txt` of the project a run page belongs to.
+pub fn parse_md5sum_link(document: &str) -> Option<Url> {
+    let html = Html::parse_document(document);
+    let links = Selector::parse("a[href]").expect("selector to be valid");
+    html.select(&links).find_map(|link| {
+        let href = link.value().attr("href").unwrap_or_default();
+        let position = href.find(GSA_DOWNLOAD_HOST)?;
+        if !href.ends_with("/md5sum.txt") {
+            return None;
+        }
+        Url::parse(&format!("https://{}", &href[position..])).ok()
+    })
+}
+
+/// Read an `md5sum.txt` into lowercase MD5s by file name.
+/// Both `<md5> <path>` and `<path> <md5>` lines are accepted.
+pub fn parse_md5sum(text: &str) -> HashMap<String, String> {
+    let mut md5s = HashMap::new();
+    for line in text.lines() {
+        let mut tokens = line.split_whitespace();
+        let (Some(first), Some(second)) = (tokens.next(), tokens.next()) else {
+            continue;
+        };
+        let (md5, path) = if is_md5(first) {
+            (first, second)
+        } else if is_md5(second) {
+            (second, first)
+        } else {
+            continue;
+        };
+        // `*` marks files hashed in binary mode.
+        let path = path.trim_start_matches('*');
+        let name = path.rsplit('/').next().unwrap_or(path);
+        md5s.insert(name.to_owned(), md5.to_ascii_lowercase());
+    }
+    md5s
+}
+
+pub fn is_md5(text: &str) -> bool {
+    text.len() == 32 && text.chars().all(|char| char.is_ascii_hexdigit())
+}
+
+/// Text of an element with surrounding whitespace removed.
+fn text_of(element: &ElementRef) -> String {
+    element.text().collect::<String>().trim().to_owned()
+}
+
+/// Fetch a page from CNCB.
+/// This function will retry network requests.
+async fn read_page(
+    client: &NetworkInstance,
+    url: String,
+    config: &Config,
+) -> Result<String, ReadMetaError> {
+    let bytes = crate::with_retry!(
+        config.into() =>
+            client.get_by_str(url.clone(), config.read_meta_timeout)
+    )?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Resolve a CRA, PRJCA or SAMC accession to the experiments it holds as `(CRA, CRX)`.
+/// Projects are expanded through the CRAs they link to,
+/// samples only to the experiments linked from them.
+/// Linked GSA-Human data is left out, and only reported when nothing else is there.
+/// This function will retry network requests.
+pub async fn read_experiments(
     client: NetworkInstance,
-    cra: usize,
-    crx: usize,
-    config: Arc<Config>,
-) -> Result<(String, Vec<String>, usize), ()> {
-    let result = crate::with_retry!(
-        config.retry_times =>
-            client.get(Url::from_str(&format!(
-                "https://ngdc.cncb.ac.cn/gsa/browse/CRA{:0>6}/CRX{:0>6}",
-                cra, crx
-            )).unwrap(),config.read_meta_timeout
-    ));
-    if let Err(_) = result {
-        return Err(());
-    }
-    let bytes = result.unwrap();
-    let document = String::from_utf8_lossy(&bytes[2000..]).to_string();
-    drop(bytes);
-    let document = document[..document.find(r#"<div class="col-md-3">"#).unwrap()].to_string();
-    let crr_number = {
-        let crr_pos = document
-            .find(&format!(r#"<td><a href="browse/CRA{:0>6}/"#, cra))
-            .unwrap();
-        let mut crr_number = document[(crr_pos + 30)..(crr_pos + 40)].to_owned();
-        crr_number.retain(|char| char.is_numeric());
-        crr_number.parse().expect("crr to be number")
-    }; // CRR read, proceed to get alias
-
-    let result = crate::with_retry!(
-        config.retry_times =>
-            client.get(Url::from_str(&format!(
-                "https://ngdc.cncb.ac.cn/gsa/browse/CRA{:0>6}/CRR{:0>6}",
-                cra, crr_number
-            )).unwrap(),config.read_meta_timeout
-    ));
-    if let Err(_) = result {
-        return Err(());
-    }
-    let bytes = result.unwrap();
-    let document = String::from_utf8_lossy(&bytes[6600..(bytes.len()-100)]).to_string();
-    drop(bytes);
-    let (alias, filename) = {
-        let alias_regex = regex::Regex::new(&format!(
-            "<td>CRR{:0>6}</td>[^a-z]+?<td>.*</td>",
-            crr_number
-        ))
-        .unwrap();
-        let alias = &alias_regex.find(&document).unwrap().as_str()[25..];
-        let alias_regex = regex::Regex::new("td>[^<>/]*</").unwrap();
-        let alias = alias_regex.find(alias).unwrap();
-        let alias = alias.as_str()[3..alias.as_str().len() - 2].to_owned();
-        let name_regex = regex::Regex::new(&format!(
-            r#"download.cncb.ac.cn/gsa/CRA{:0>6}/CRR{:0>6}/[^<>"]*""#,
-            cra, crr_number
-        ))
-        .unwrap();
-        let name_search_result = name_regex.find_iter(&document);
-        (
-            alias,
-            name_search_result
-                .map(|matched| {
-                    let matched_str = matched.as_str();
-                    matched_str[44..matched_str.len() - 1].to_owned()
-                })
-                .collect::<Vec<String>>(),
-        )
+    accession: &str,
+    config: &Config,
+) -> Result<Vec<(String, String)>, ReadMetaError> {
+    let (url, expand_cra) = if accession.starts_with("PRJCA") {
+        (format!("{}/{}", BIOPROJECT_BROWSE, accession), true)
+    } else if accession.starts_with("SAMC") {
+        (format!("{}/{}", BIOSAMPLE_BROWSE, accession), false)
+    } else {
+        (format!("{}/{}", GSA_BROWSE, accession), false)
     };
-    Ok((alias, filename, crr_number))
+    let document = read_page(&client, url, config).await?;
+    let mut experiments = Vec::new();
+    for (cra, crx) in parse_experiment_links(&document) {
+        if accession.starts_with("CRA") && cra != accession {
+            continue;
+        }
+        if !crx.is_empty() {
+            experiments.push((cra, crx));
+        } else if expand_cra {
+            let document = read_page(&client, format!("{}/{}", GSA_BROWSE, cra), config).await?;
+            experiments.extend(
+                parse_experiment_links(&document)
+                    .into_iter()
+                    .filter(|(linked_cra, crx)| *linked_cra == cra && !crx.is_empty()),
+            );
+        }
+    }
+    let controlled = parse_controlled_links(&document);
+    if !controlled.is_empty() {
+        crate::progress!(
+            "Skipping {} of {}: controlled access on GSA-Human",
+            controlled.join(", "),
+            accession
+        );
+    }
+    if experiments.is_empty() && !controlled.is_empty() {
+        return Err(ReadMetaError::ControlledAccess(accession.to_owned()));
+    }
+    if experiments.is_empty() {
+        return Err(ReadMetaError::NotFound(accession.to_owned()));
+    }
+    Ok(experiments)
+}
+
+/// Resolve an experiment to all of its runs, with the alias and files of each.
+/// MD5s missing from a run page are looked up in the `md5sum.txt` of the project.
+/// This function will retry network requests.
+pub async fn read_runs_with_crx(
+    client: NetworkInstance,
+    cra: &str,
+    crx: &str,
+    config: &Config,
+) -> Result<Vec<GsaRun>, ReadMetaError> {
+    let document = read_page(&client, format!("{}/{}/{}", GSA_BROWSE, cra, crx), config).await?;
+    let mut runs = Vec::new();
+    let mut md5sum = None;
+    for crr in parse_crx_page(&document, crx)? {
+        let document =
+            read_page(&client, format!("{}/{}/{}", GSA_BROWSE, cra, crr), config).await?;
+        let mut run = parse_crr_page(&document, &crr)?;
+        if run.files.iter().any(|file| file.md5.is_none()) {
+            if let (None, Some(url)) = (&md5sum, parse_md5sum_link(&document)) {
+                md5sum = match read_page(&client, url.to_string(), config).await {
+                    Ok(text) => Some(parse_md5sum(&text)),
+                    Err(e) => {
+                        crate::progress!("Cannot read {}: {}", url, e);
+                        Some(HashMap::new())
+                    }
+                };
+            }
+            for file in run.files.iter_mut().filter(|file| file.md5.is_none()) {
+                file.md5 = md5sum
+                    .as_ref()
+                    .and_then(|md5s| md5s.get(&file.name))
+                    .cloned();
+            }
+        }
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+/// Name the files of runs as `<alias>_<file name>`.
+/// When runs share an alias and the file name does not tell them apart,
+/// the run accession goes in between.
+pub fn file_names(runs: &[GsaRun]) -> Vec<(String, GsaFile)> {
+    let mut names = Vec::new();
+    for run in runs.iter() {
+        let shared_alias = runs
+            .iter()
+            .any(|other| other.crr != run.crr && other.alias == run.alias);
+        for file in run.files.iter() {
+            let name = if shared_alias && !file.name.contains(&run.crr) {
+                format!("{}_{}_{}", run.alias, run.crr, file.name)
+            } else {
+                format!("{}_{}", run.alias, file.name)
+            };
+            names.push((name, file.clone()));
+        }
+    }
+    names
 }
 
 pub async fn preflight(
     client: NetworkInstance,
-    cra: usize,
-    crx: usize,
+    cra: String,
+    crx: String,
     config: Arc<Config>,
-) -> Result<(usize, ()), ()> {
-    match read_alias_and_crr_with_crx(client.clone(), cra, crx, config).await {
-        Ok((alias, filenames, crr)) => {
-            println!(
-                "Found CRR{} with files: {:?}; alias: {}",
-                crr, filenames, alias
-            );
-            Ok((crx, ()))
+) -> Result<Vec<GsaRun>, TaskError> {
+    match read_runs_with_crx(client.clone(), &cra, &crx, config.as_ref()).await {
+        Ok(runs) => {
+            for run in runs.iter() {
+                crate::progress!(
+                    "Found {} in {} with files: {:?}; alias: {}",
+                    run.crr,
+                    crx,
+                    run.files.iter().map(|file| &file.name).collect::<Vec<_>>(),
+                    run.alias
+                );
+            }
+            Ok(runs)
         }
-        Err(_) => {
-            println!(
-                "Preflight for CRA{}/CRX{} failed: Maximum retry times reached",
-                cra, crx
-            );
-            Err(())
+        Err(e) => {
+            crate::progress!("Preflight for {}/{} failed: {}", cra, crx, e);
+            Err(TaskError::resolving(&crx, &e))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written after the markup of GSA for the error paths, which live pages
+    // do not show. `live_pages_are_parsed` checks the parsers against GSA itself.
+    const CRX_PAGE: &str = include_str!("../tests/fixtures/gsa_crx.html");
+    const CRR_PAGE: &str = include_str!("../tests/fixtures/gsa_crr.html");
+
+    #[tokio::test]
+    #[ignore = "needs access to ngdc.cncb.ac.cn"]
+    async fn live_pages_are_parsed() {
+        let config = Config::default();
+        let client = NetworkInstance::new(&config);
+        let experiments = read_experiments(client.clone(), "CRA004538", &config)
+            .await
+            .unwrap();
+        assert!(experiments.contains(&("CRA004538".to_owned(), "CRX264201".to_owned())));
+        let runs = read_runs_with_crx(client, "CRA004538", "CRX264201", &config)
+            .await
+            .unwrap();
+        assert!(!runs.is_empty());
+        for run in runs {
+            assert!(run.crr.starts_with("CRR"));
+            assert!(!run.alias.is_empty());
+            assert!(!run.files.is_empty());
+            for file in run.files {
+                assert_eq!(file.url.host_str(), Some("download.cncb.ac.cn"));
+                assert!(file.md5.as_deref().is_none_or(is_md5));
+            }
+        }
+    }
+
+    #[test]
+    fn crx_page_lists_runs() {
+        let crrs = parse_crx_page(CRX_PAGE, "CRX012345").unwrap();
+        assert_eq!(crrs, vec!["CRR0123456".to_owned(), "CRR0123457".to_owned()]);
+    }
+
+    #[test]
+    fn crx_page_without_runs_is_not_found() {
+        let result = parse_crx_page("<html><body><table></table></body></html>", "CRX1");
+        assert!(matches!(result, Err(ReadMetaError::NotFound(_))));
+    }
+
+    #[test]
+    fn project_page_links_experiments() {
+        let document = r#"<html><body><table>
+            <tr><td><a href="https://ngdc.cncb.ac.cn/gsa/browse/CRA012345">CRA012345</a></td></tr>
+            <tr><td><a href="https://ngdc.cncb.ac.cn/gsa/browse/CRA012346/CRX012399">CRX012399</a></td></tr>
+            <tr><td><a href="https://ngdc.cncb.ac.cn/gsa/browse/CRA012346/CRX012399/">CRX012399</a></td></tr>
+            <tr><td><a href="https://ngdc.cncb.ac.cn/gsa-human/browse/HRA000123">HRA000123</a></td></tr>
+        </table></body></html>"#;
+        assert_eq!(
+            parse_experiment_links(document),
+            vec![
+                ("CRA012345".to_owned(), "".to_owned()),
+                ("CRA012346".to_owned(), "CRX012399".to_owned())
+            ]
+        );
+        assert_eq!(parse_controlled_links(document), vec!["HRA000123"]);
+        assert!(is_controlled_accession("HRA000123"));
+        assert!(!is_controlled_accession("CRA012345"));
+        // Runs on a CRX page are not experiments.
+        assert_eq!(
+            parse_experiment_links(CRX_PAGE),
+            vec![("CRA012345".to_owned(), "".to_owned())]
+        );
+    }
+
+    #[test]
+    fn crr_page_gives_alias_and_files() {
+        let run = parse_crr_page(CRR_PAGE, "CRR0123456").unwrap();
+        assert_eq!(run.alias, "liver_rep1");
+        assert_eq!(
+            run.files
+                .iter()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["CRR0123456_f1.fq.gz", "CRR0123456_r2.fq.gz"]
+        );
+        assert_eq!(
+            run.files[0].url.as_str(),
+            "https://download.cncb.ac.cn/gsa/CRA012345/CRR0123456/CRR0123456_f1.fq.gz"
+        );
+        assert_eq!(
+            run.files
+                .iter()
+                .map(|file| file.md5.as_deref())
+                .collect::<Vec<_>>(),
+            vec![
+                Some("0cc175b9c0f1b6a831c399e269772661"),
+                Some("92eb5ffee6ae2fec3ad71c777531578f")
+            ]
+        );
+    }
+
+    #[test]
+    fn md5sum_fills_in_for_the_page() {
+        assert_eq!(
+            parse_md5sum_link(CRR_PAGE).unwrap().as_str(),
+            "https://download.cncb.ac.cn/gsa/CRA012345/md5sum.txt"
+        );
+        let md5s = parse_md5sum(
+            "0CC175B9C0F1B6A831C399E269772661  CRR0123456/CRR0123456_f1.fq.gz\n\
+             92eb5ffee6ae2fec3ad71c777531578f *CRR0123456_r2.fq.gz\n\
+             CRR0123457_f1.fq.gz\t4a8a08f09d37b73795649038408b5f33\n\
+             not a checksum line\n",
+        );
+        assert_eq!(
+            md5s.get("CRR0123456_f1.fq.gz").map(String::as_str),
+            Some("0cc175b9c0f1b6a831c399e269772661")
+        );
+        assert_eq!(
+            md5s.get("CRR0123456_r2.fq.gz").map(String::as_str),
+            Some("92eb5ffee6ae2fec3ad71c777531578f")
+        );
+        assert_eq!(
+            md5s.get("CRR0123457_f1.fq.gz").map(String::as_str),
+            Some("4a8a08f09d37b73795649038408b5f33")
+        );
+        assert_eq!(md5s.len(), 3);
+    }
+
+    #[test]
+    fn shared_alias_is_disambiguated_by_run() {
+        let file = |crr: &str| GsaFile {
+            name: "reads_1.fq.gz".to_owned(),
+            url: Url::parse(&format!(
+                "https://download.cncb.ac.cn/gsa/CRA012345/{}/reads_1.fq.gz",
+                crr
+            ))
+            .unwrap(),
+            md5: None,
+        };
+        let run = |crr: &str| GsaRun {
+            crr: crr.to_owned(),
+            alias: "liver_rep1".to_owned(),
+            files: vec![file(crr)],
+        };
+        let names = file_names(&[run("CRR0123456"), run("CRR0123457")]);
+        assert_eq!(
+            names.into_iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec![
+                "liver_rep1_CRR0123456_reads_1.fq.gz",
+                "liver_rep1_CRR0123457_reads_1.fq.gz"
+            ]
+        );
+        let names = file_names(&[parse_crr_page(CRR_PAGE, "CRR0123456").unwrap()]);
+        assert_eq!(names[0].0, "liver_rep1_CRR0123456_f1.fq.gz");
+    }
+
+    #[test]
+    fn crr_page_without_alias_is_missing_field() {
+        let document = CRR_PAGE.replace("liver_rep1", " ");
+        let result = parse_crr_page(&document, "CRR0123456");
+        assert!(matches!(
+            result,
+            Err(ReadMetaError::MissingField { field: "alias", .. })
+        ));
+    }
+
+    #[test]
+    fn crr_page_without_files_is_missing_field() {
+        let document = CRR_PAGE.replace("download.cncb.ac.cn", "example.org");
+        let result = parse_crr_page(&document, "CRR0123456");
+        assert!(matches!(
+            result,
+            Err(ReadMetaError::MissingField {
+                field: "file list",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+use regex::Regex;
+use reqwest::Url;
+
+use crate::{
+    mirror::RunFile,
+    ncbi::{self, ReadMetaError},
+    Config, NetworkInstance,
+};
+
+const DRA_FASTQ: &str = "https://ddbj.nig.ac.jp/public/ddbj_database/dra/fastq";
+
+/// The FASTQ files of a run on the DDBJ Sequence Read Archive.
+/// DRA keeps them bzip2 compressed under `<submission>/<experiment>/`,
+/// which is looked up in the SRA metadata and then listed.
+/// This function will retry network requests.
+pub async fn run_files(
+    client: &NetworkInstance,
+    srr: &str,
+    config: &Config,
+) -> Result<Vec<RunFile>, ReadMetaError> {
+    let run = ncbi::fetch_run_info(client, srr, config)
+        .await?
+        .into_iter()
+        .find(|run| run.run == srr)
+        .ok_or(ReadMetaError::NotFound(srr.to_owned()))?;
+    if run.is_controlled() {
+        return Err(ReadMetaError::ControlledAccess(srr.to_owned()));
+    }
+    if run.submission.len() < 6 {
+        return Err(ReadMetaError::MissingField {
+            accession: srr.to_owned(),
+            field: "submission",
+        });
+    }
+    let directory = Url::parse(&format!(
+        "{}/{}/{}/{}/",
+        DRA_FASTQ,
+        &run.submission[..6],
+        run.submission,
+        run.experiment
+    ))
+    .map_err(|e| ReadMetaError::Malformed(format!("DRA directory of {}: {}", srr, e)))?;
+    let bytes = crate::with_retry!(
+        config.into() =>
+            client.get(directory.clone(), config.read_meta_timeout)
+    )?;
+    let listing = String::from_utf8_lossy(&bytes);
+    let file_regex = Regex::new(&format!(r#"href="({}((?:_\d)?\.fastq\.bz2))""#, srr))
+        .expect("regex to be valid");
+    let mut files = Vec::new();
+    for captures in file_regex.captures_iter(&listing) {
+        let url = directory
+            .join(&captures[1])
+            .map_err(|e| ReadMetaError::Malformed(format!("DRA file of {}: {}", srr, e)))?;
+        // DRA publishes no checksums next to the files.
+        if !files.iter().any(|known: &RunFile| known.url == url) {
+            files.push(RunFile {
+                suffix: captures[2].to_owned(),
+                url,
+                md5: None,
+            });
+        }
+    }
+    if files.is_empty() {
+        return Err(ReadMetaError::NotFound(format!(
+            "FASTQ files of {} on DDBJ",
+            srr
+        )));
+    }
+    Ok(files)
+}
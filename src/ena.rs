@@ -0,0 +1,215 @@
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    cnbi::is_md5,
+    mirror::RunFile,
+    ncbi::{self, NamedRun, ReadCounts, ReadMetaError},
+    Config, NetworkInstance,
+};
+
+const FILEREPORT: &str = "https://www.ebi.ac.uk/ena/portal/api/filereport";
+const FIELDS: &str = "run_accession,sample_alias,library_name,fastq_ftp,fastq_md5,\
+                      read_count,base_count,library_layout";
+
+/// One row of the ENA filereport of `read_run` results.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnaRun {
+    pub run_accession: String,
+    #[serde(default)]
+    pub sample_alias: String,
+    #[serde(default)]
+    pub library_name: String,
+    /// `;` separated locations of the FASTQ files, without a scheme.
+    #[serde(default)]
+    pub fastq_ftp: String,
+    /// `;` separated MD5s of the FASTQ files, in the order of `fastq_ftp`.
+    #[serde(default)]
+    pub fastq_md5: String,
+    /// Number of spots, as a string like every other field.
+    #[serde(default)]
+    pub read_count: String,
+    #[serde(default)]
+    pub base_count: String,
+    #[serde(default)]
+    pub library_layout: String,
+}
+impl EnaRun {
+    /// Spots and bases ENA reports for the run, when both are known.
+    pub fn read_counts(&self) -> Option<ReadCounts> {
+        Some(ReadCounts {
+            spots: self.read_count.parse().ok()?,
+            bases: self.base_count.parse().ok()?,
+        })
+    }
+    /// The FASTQ files of the run, with their MD5s when ENA lists one for every file.
+    pub fn files(&self) -> Result<Vec<RunFile>, ReadMetaError> {
+        let urls = self.fastq_urls()?;
+        if urls.is_empty() {
+            return Err(ReadMetaError::MissingField {
+                accession: self.run_accession.clone(),
+                field: "fastq_ftp",
+            });
+        }
+        let md5s = self.fastq_md5.split(';').collect::<Vec<_>>();
+        // Checksums are only of use when they line up with the files.
+        let md5s = if md5s.len() == urls.len() && md5s.iter().all(|md5| is_md5(md5)) {
+            md5s.into_iter().map(|md5| Some(md5.to_owned())).collect()
+        } else {
+            vec![None; urls.len()]
+        };
+        Ok(urls
+            .into_iter()
+            .zip(md5s)
+            .map(|(url, md5)| {
+                let file_name = url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .unwrap_or_default();
+                let suffix = file_name
+                    .strip_prefix(self.run_accession.as_str())
+                    .unwrap_or(".fastq.gz")
+                    .to_owned();
+                RunFile { suffix, url, md5 }
+            })
+            .collect())
+    }
+    /// HTTPS URLs of the FASTQ files of the run.
+    pub fn fastq_urls(&self) -> Result<Vec<Url>, ReadMetaError> {
+        self.fastq_ftp
+            .split(';')
+            .filter(|location| !location.is_empty())
+            .map(|location| {
+                Url::parse(&format!("https://{}", location)).map_err(|e| {
+                    ReadMetaError::Malformed(format!("fastq location {}: {}", location, e))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read the filereport of every run under `accession`,
+/// which may be a run, experiment, sample or study.
+/// This function will retry network requests.
+pub async fn read_ena_runs(
+    client: &NetworkInstance,
+    accession: &str,
+    config: &Config,
+) -> Result<Vec<EnaRun>, ReadMetaError> {
+    let bytes = crate::with_retry!(
+        config.into() =>
+            client.get_by_str(format!(
+                "{}?accession={}&result=read_run&fields={}&format=json",
+                FILEREPORT, accession, FIELDS
+            ), config.read_meta_timeout)
+    )?;
+    // An accession without runs gets an empty body.
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Err(ReadMetaError::NotFound(accession.to_owned()));
+    }
+    let runs = serde_json::from_slice::<Vec<EnaRun>>(&bytes)
+        .map_err(|e| ReadMetaError::Malformed(format!("filereport for {}: {}", accession, e)))?;
+    if runs.is_empty() {
+        return Err(ReadMetaError::NotFound(accession.to_owned()));
+    }
+    Ok(runs)
+}
+
+/// Resolve `accession` to its runs on ENA, paired with the name to save them under.
+/// Names follow the same arguments as on NCBI.
+/// This function does not spawn additional task.
+/// This function will retry network requests.
+pub async fn read_names_and_srrs(
+    client: NetworkInstance,
+    accession: &str,
+    config: &Config,
+    arguments: &[String],
+) -> Result<Vec<NamedRun>, ReadMetaError> {
+    let runs = read_ena_runs(&client, accession, config).await?;
+    let runs = runs
+        .iter()
+        .map(|run| {
+            let (name, field) = if arguments.contains(&"srr_override".into()) {
+                (&run.run_accession, "run accession")
+            } else if arguments.contains(&"library_name_override".into()) {
+                (&run.library_name, "library name")
+            } else {
+                (&run.sample_alias, "sample alias")
+            };
+            if name.trim().is_empty() {
+                return Err(ReadMetaError::MissingField {
+                    accession: run.run_accession.clone(),
+                    field,
+                });
+            }
+            Ok(NamedRun {
+                name: name.trim().to_owned(),
+                srr: run.run_accession.clone(),
+                counts: run.read_counts(),
+                paired: ncbi::layout_is_paired(&run.library_layout),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ncbi::disambiguate(runs))
+}
+
+/// The FASTQ files of a run on ENA with their published MD5s.
+/// This function will retry network requests.
+pub async fn run_files(
+    client: &NetworkInstance,
+    srr: &str,
+    config: &Config,
+) -> Result<Vec<RunFile>, ReadMetaError> {
+    let run = read_ena_runs(client, srr, config)
+        .await?
+        .into_iter()
+        .find(|run| run.run_accession == srr)
+        .ok_or(ReadMetaError::NotFound(srr.to_owned()))?;
+    run.files()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(fastq_ftp: &str, fastq_md5: &str) -> EnaRun {
+        EnaRun {
+            run_accession: "SRR1".to_owned(),
+            sample_alias: String::new(),
+            library_name: String::new(),
+            fastq_ftp: fastq_ftp.to_owned(),
+            fastq_md5: fastq_md5.to_owned(),
+            read_count: String::new(),
+            base_count: String::new(),
+            library_layout: String::new(),
+        }
+    }
+
+    #[test]
+    fn files_come_with_their_md5() {
+        let md5 = [
+            "0123456789abcdef0123456789abcdef",
+            "fedcba9876543210fedcba9876543210",
+        ];
+        let files = run(
+            "ftp.sra.ebi.ac.uk/vol1/SRR1_1.fastq.gz;ftp.sra.ebi.ac.uk/vol1/SRR1_2.fastq.gz",
+            &md5.join(";"),
+        )
+        .files()
+        .unwrap();
+        assert_eq!(files[0].suffix, "_1.fastq.gz");
+        assert_eq!(
+            files[0].url.as_str(),
+            "https://ftp.sra.ebi.ac.uk/vol1/SRR1_1.fastq.gz"
+        );
+        assert_eq!(files[0].md5.as_deref(), Some(md5[0]));
+        assert_eq!(files[1].suffix, "_2.fastq.gz");
+        assert_eq!(files[1].md5.as_deref(), Some(md5[1]));
+        // Checksums that do not line up with the files are not used.
+        let files = run("ftp.sra.ebi.ac.uk/vol1/SRR1.fastq.gz", &md5.join(";"))
+            .files()
+            .unwrap();
+        assert_eq!(files[0].md5, None);
+        assert!(run("", "").files().is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+/// Why a FASTQ file did not pass validation.
+#[derive(Debug)]
+pub enum FastqError {
+    /// The file could not be read or decompressed, e.g. a truncated gzip stream.
+    Io(std::io::Error),
+    /// The file ends in the middle of a record.
+    Truncated { record: u64 },
+    /// A record does not start with `@`.
+    MalformedHeader { line: u64 },
+    /// The third line of a record does not start with `+`.
+    MalformedSeparator { line: u64 },
+    /// Sequence and quality of a record differ in length.
+    LengthMismatch { line: u64 },
+}
+impl std::fmt::Display for FastqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Cannot read FASTQ: {}", e),
+            Self::Truncated { record } => write!(f, "Record {} is incomplete", record),
+            Self::MalformedHeader { line } => write!(f, "Line {} is not a record header", line),
+            Self::MalformedSeparator { line } => {
+                write!(f, "Line {} is not a record separator", line)
+            }
+            Self::LengthMismatch { line } => write!(
+                f,
+                "Quality on line {} does not match the length of the sequence",
+                line
+            ),
+        }
+    }
+}
+impl From<std::io::Error> for FastqError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Whether the file is a gzip compressed FASTQ that can be validated.
+pub fn is_fastq_gz(file_path: &str) -> bool {
+    file_path.ends_with(".fastq.gz") || file_path.ends_with(".fq.gz")
+}
+
+/// Reads and bases found in a FASTQ.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FastqStats {
+    pub reads: u64,
+    pub bases: u64,
+}
+impl std::ops::Add for FastqStats {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            reads: self.reads + rhs.reads,
+            bases: self.bases + rhs.bases,
+        }
+    }
+}
+
+/// Decompress a `.fastq.gz` file completely and check every record.
+/// This function blocks, run it on a blocking thread.
+pub fn validate_file(file_path: &str) -> Result<FastqStats, FastqError> {
+    let file = File::open(file_path)?;
+    validate(MultiGzDecoder::new(BufReader::new(file)))
+}
+
+/// Check that the FASTQ read from `reader` consists of well-formed 4-line records,
+/// counting them and their bases.
+pub fn validate(reader: impl Read) -> Result<FastqStats, FastqError> {
+    let mut reader = BufReader::new(reader);
+    let mut lines = [String::new(), String::new(), String::new(), String::new()];
+    let mut stats = FastqStats::default();
+    loop {
+        for (index, line) in lines.iter_mut().enumerate() {
+            line.clear();
+            if reader.read_line(line)? == 0 {
+                if index == 0 {
+                    return Ok(stats);
+                }
+                return Err(FastqError::Truncated {
+                    record: stats.reads + 1,
+                });
+            }
+            let trimmed = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(trimmed);
+        }
+        let line = stats.reads * 4 + 1;
+        if !lines[0].starts_with('@') {
+            return Err(FastqError::MalformedHeader { line });
+        }
+        if !lines[2].starts_with('+') {
+            return Err(FastqError::MalformedSeparator { line: line + 2 });
+        }
+        if lines[1].len() != lines[3].len() {
+            return Err(FastqError::LengthMismatch { line: line + 3 });
+        }
+        stats.reads += 1;
+        stats.bases += lines[1].len() as u64;
+    }
+}
+
+/// Splits an interleaved FASTQ written into it between two gzip compressed outputs,
+/// the first mate of every spot going to the first one.
+/// Mates are checked to share the name of their spot.
+pub struct Deinterleaver<W: Write> {
+    mates: [GzEncoder<W>; 2],
+    /// Lines of the record being written, the last one possibly incomplete.
+    record: Vec<u8>,
+    lines: usize,
+    /// Name of the spot the first mate was written for.
+    spot: Vec<u8>,
+    next_mate: usize,
+    records: u64,
+}
+impl<W: Write> Deinterleaver<W> {
+    pub fn new(mates: [W; 2]) -> Self {
+        Self {
+            mates: mates.map(|mate| GzEncoder::new(mate, Compression::default())),
+            record: Vec::new(),
+            lines: 0,
+            spot: Vec::new(),
+            next_mate: 0,
+            records: 0,
+        }
+    }
+    /// Complete both outputs, failing if the input ended in the middle of a spot.
+    pub fn finish(self) -> io::Result<[W; 2]> {
+        if !self.record.is_empty() || self.next_mate != 0 {
+            return Err(invalid_data(format!(
+                "interleaved FASTQ ends within spot {}",
+                self.records / 2 + 1
+            )));
+        }
+        let [first, second] = self.mates;
+        Ok([first.finish()?, second.finish()?])
+    }
+    /// Send the complete record to its mate.
+    fn write_record(&mut self) -> io::Result<()> {
+        self.records += 1;
+        if self.record.first() != Some(&b'@') {
+            return Err(invalid_data(format!(
+                "record {} is not a FASTQ record",
+                self.records
+            )));
+        }
+        let header = self.record[1..]
+            .split(|byte| byte.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default();
+        // Older dumps tell the mates apart by a `/1` or `/2` suffix.
+        let spot = match header {
+            [spot @ .., b'/', b'1' | b'2'] => spot,
+            spot => spot,
+        };
+        if self.next_mate == 0 {
+            self.spot = spot.to_vec();
+        } else if self.spot != spot {
+            return Err(invalid_data(format!(
+                "record {} does not pair up with the one before it",
+                self.records
+            )));
+        }
+        self.mates[self.next_mate].write_all(&self.record)?;
+        self.next_mate = 1 - self.next_mate;
+        self.record.clear();
+        self.lines = 0;
+        Ok(())
+    }
+}
+impl<W: Write> Write for Deinterleaver<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|byte| *byte == b'\n') {
+            self.record.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                self.lines += 1;
+                if self.lines == 4 {
+                    self.write_record()?;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        for mate in self.mates.iter_mut() {
+            mate.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FASTQ: &str = "@read1\nACGT\n+\nIIII\n@read2\r\nGGC\r\n+read2\r\nII#\r\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn counts_reads_and_bases_of_valid_file() {
+        let compressed = gzip(FASTQ.as_bytes());
+        assert_eq!(
+            validate(MultiGzDecoder::new(&compressed[..])).unwrap(),
+            FastqStats { reads: 2, bases: 7 }
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_gzip() {
+        let compressed = gzip(FASTQ.as_bytes());
+        let truncated = &compressed[..compressed.len() - 10];
+        assert!(matches!(
+            validate(MultiGzDecoder::new(truncated)),
+            Err(FastqError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        assert!(matches!(
+            validate("@read1\nACGT\n+\nIII\n".as_bytes()),
+            Err(FastqError::LengthMismatch { line: 4 })
+        ));
+        assert!(matches!(
+            validate("@read1\nACGT\n+\nIIII\nread2\nA\n+\nI\n".as_bytes()),
+            Err(FastqError::MalformedHeader { line: 5 })
+        ));
+        assert!(matches!(
+            validate("@read1\nACGT\n-\nIIII\n".as_bytes()),
+            Err(FastqError::MalformedSeparator { line: 3 })
+        ));
+        assert!(matches!(
+            validate("@read1\nACGT\n+\nIIII\n@read2\nA\n".as_bytes()),
+            Err(FastqError::Truncated { record: 2 })
+        ));
+    }
+
+    #[test]
+    fn splits_interleaved_mates() {
+        let interleaved = "@SRR1.1 1\nACGT\n+\nIIII\n@SRR1.1 2\nTT\n+\nII\n\
+                           @SRR1.2/1\nG\n+\nI\n@SRR1.2/2\nCC\n+\nII\n";
+        let mut deinterleaver = Deinterleaver::new([Vec::new(), Vec::new()]);
+        // Chunks do not line up with lines.
+        for chunk in interleaved.as_bytes().chunks(5) {
+            deinterleaver.write_all(chunk).unwrap();
+        }
+        let [first, second] = deinterleaver.finish().unwrap();
+        assert_eq!(
+            validate(MultiGzDecoder::new(&first[..])).unwrap(),
+            FastqStats { reads: 2, bases: 5 }
+        );
+        assert_eq!(
+            validate(MultiGzDecoder::new(&second[..])).unwrap(),
+            FastqStats { reads: 2, bases: 4 }
+        );
+    }
+
+    #[test]
+    fn rejects_unpaired_mates() {
+        let mut deinterleaver = Deinterleaver::new([Vec::new(), Vec::new()]);
+        let result = deinterleaver.write_all(b"@SRR1.1 1\nA\n+\nI\n@SRR1.2 1\nA\n+\nI\n");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut deinterleaver = Deinterleaver::new([Vec::new(), Vec::new()]);
+        deinterleaver.write_all(b"@SRR1.1 1\nA\n+\nI\n").unwrap();
+        assert!(deinterleaver.finish().is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::{
+    ncbi::{self, NamedRun, ReadCounts, ReadMetaError, EUTILS},
+    Config, NetworkInstance, TaskError,
+};
+
+/// A run of a GEO sample.
+#[derive(Debug, Clone)]
+pub struct GeoRun {
+    pub gsm: String,
+    pub title: String,
+    pub srr: String,
+    pub library_name: String,
+    pub counts: Option<ReadCounts>,
+    pub paired: Option<bool>,
+}
+
+/// The part of a GEO DataSets summary we make use of.
+#[derive(Debug, Deserialize)]
+struct GdsSummary {
+    accession: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    samples: Vec<GdsSample>,
+    #[serde(default)]
+    extrelations: Vec<GdsRelation>,
+}
+#[derive(Debug, Deserialize)]
+struct GdsSample {
+    accession: String,
+}
+#[derive(Debug, Deserialize)]
+struct GdsRelation {
+    #[serde(default)]
+    relationtype: String,
+    #[serde(default)]
+    targetobject: String,
+}
+
+/// Read the summary of a GSE or GSM from GEO DataSets.
+/// This function will retry network requests.
+async fn read_summary(
+    client: &NetworkInstance,
+    accession: &str,
+    config: &Config,
+) -> Result<GdsSummary, ReadMetaError> {
+    let search = ncbi::esearch(client, "gds", &format!("{}[ACCN]", accession), config).await?;
+    if search.idlist.is_empty() {
+        return Err(ReadMetaError::NotFound(accession.to_owned()));
+    }
+    let bytes = crate::with_retry!(
+        config.into() =>
+            client.get_by_str(format!(
+                "{}/esummary.fcgi?db=gds&id={}&retmode=json",
+                EUTILS, search.idlist.join(",")
+            ), config.read_meta_timeout)
+    )?;
+    let malformed = |e: serde_json::Error| {
+        ReadMetaError::Malformed(format!("esummary for {}: {}", accession, e))
+    };
+    let mut response = serde_json::from_slice::<serde_json::Value>(&bytes).map_err(malformed)?;
+    for uid in search.idlist.iter() {
+        let summary = response["result"][uid].take();
+        if summary.is_null() {
+            continue;
+        }
+        let summary = serde_json::from_value::<GdsSummary>(summary).map_err(malformed)?;
+        if summary.accession == accession {
+            return Ok(summary);
+        }
+    }
+    Err(ReadMetaError::NotFound(accession.to_owned()))
+}
+
+/// Resolve a GSM to its runs through the SRA experiment it links to.
+/// This function will retry network requests.
+async fn read_gsm_runs(
+    client: &NetworkInstance,
+    gsm: &str,
+    config: &Config,
+) -> Result<Vec<GeoRun>, ReadMetaError> {
+    let summary = read_summary(client, gsm, config).await?;
+    if summary.title.trim().is_empty() {
+        return Err(ReadMetaError::MissingField {
+            accession: gsm.to_owned(),
+            field: "sample title",
+        });
+    }
+    let mut runs = Vec::new();
+    let mut controlled = false;
+    for relation in summary.extrelations.iter() {
+        if relation.relationtype != "SRA" {
+            continue;
+        }
+        let experiment = relation.targetobject.as_str();
+        for run in ncbi::fetch_run_info(client, experiment, config).await? {
+            if !run.belongs_to(experiment) {
+                continue;
+            }
+            if run.is_controlled() {
+                crate::progress!(
+                    "Skipping {} of {}: controlled access on dbGaP, consent {}",
+                    run.run,
+                    gsm,
+                    run.consent
+                );
+                controlled = true;
+            } else {
+                runs.push(GeoRun {
+                    gsm: gsm.to_owned(),
+                    title: summary.title.trim().to_owned(),
+                    counts: run.read_counts(),
+                    paired: run.is_paired(),
+                    library_name: run.library_name.trim().to_owned(),
+                    srr: run.run,
+                });
+            }
+        }
+    }
+    if runs.is_empty() && controlled {
+        return Err(ReadMetaError::ControlledAccess(gsm.to_owned()));
+    }
+    if runs.is_empty() {
+        return Err(ReadMetaError::NotFound(format!("SRA runs of {}", gsm)));
+    }
+    Ok(runs)
+}
+
+/// Resolve a GSE or GSM to the SRA runs of its samples.
+/// This function does not spawn additional task.
+/// This function will retry network requests.
+pub async fn read_geo_runs(
+    client: NetworkInstance,
+    accession: &str,
+    config: &Config,
+) -> Result<Vec<GeoRun>, ReadMetaError> {
+    if accession.starts_with("GSM") {
+        return read_gsm_runs(&client, accession, config).await;
+    }
+    let summary = read_summary(&client, accession, config).await?;
+    if summary.samples.is_empty() {
+        return Err(ReadMetaError::NotFound(format!("samples of {}", accession)));
+    }
+    let mut runs = Vec::new();
+    let mut controlled = false;
+    for sample in summary.samples.iter() {
+        match read_gsm_runs(&client, &sample.accession, config).await {
+            Ok(v) => runs.extend(v),
+            // Series may hold array samples without SRA runs, or protected ones.
+            Err(
+                e @ (ReadMetaError::NotFound(_)
+                | ReadMetaError::MissingField { .. }
+                | ReadMetaError::ControlledAccess(_)),
+            ) => {
+                crate::progress!("Skipping {} of {}: {}", sample.accession, accession, e);
+                controlled |= e.is_controlled_access();
+            }
+            // A sample that may resolve on another try is not left out.
+            Err(e) => return Err(e),
+        }
+    }
+    if runs.is_empty() && controlled {
+        return Err(ReadMetaError::ControlledAccess(accession.to_owned()));
+    }
+    if runs.is_empty() {
+        return Err(ReadMetaError::NotFound(format!(
+            "SRA runs of {}",
+            accession
+        )));
+    }
+    Ok(runs)
+}
+
+/// Resolve a GSE or GSM and name every run after the title of its sample,
+/// or after its run accession or library name as the line arguments ask.
+/// Titles are made safe to use as file names, and the run accession
+/// is appended where a sample has several runs.
+pub async fn read_names_and_srrs(
+    client: NetworkInstance,
+    accession: &str,
+    config: &Config,
+    arguments: &[String],
+) -> Result<Vec<NamedRun>, ReadMetaError> {
+    let runs = read_geo_runs(client, accession, config).await?;
+    name_runs(runs, arguments)
+}
+
+/// Name the runs as the line arguments ask.
+fn name_runs(runs: Vec<GeoRun>, arguments: &[String]) -> Result<Vec<NamedRun>, ReadMetaError> {
+    let runs = runs
+        .into_iter()
+        .map(|run| {
+            let name = if arguments.contains(&"srr_override".into()) {
+                run.srr.clone()
+            } else if arguments.contains(&"library_name_override".into()) {
+                if run.library_name.is_empty() {
+                    return Err(ReadMetaError::MissingField {
+                        accession: run.srr,
+                        field: "library name",
+                    });
+                }
+                file_name_of(&run.library_name)
+            } else {
+                file_name_of(&run.title)
+            };
+            Ok(NamedRun {
+                name,
+                srr: run.srr,
+                counts: run.counts,
+                paired: run.paired,
+            })
+        })
+        .collect::<Result<Vec<_>, ReadMetaError>>()?;
+    Ok(ncbi::disambiguate(runs))
+}
+
+/// Replace everything that may not be used in a file name.
+fn file_name_of(title: &str) -> String {
+    title
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() || matches!(char, '.' | '-' | '_') {
+                char
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub async fn preflight(
+    client: NetworkInstance,
+    accession: String,
+    config: Arc<Config>,
+) -> Result<Vec<GeoRun>, TaskError> {
+    match read_geo_runs(client, &accession, config.as_ref()).await {
+        Ok(runs) => {
+            for run in runs.iter() {
+                crate::progress!("Found {} ({}) with {}", run.gsm, run.title, run.srr);
+            }
+            Ok(runs)
+        }
+        Err(e) => {
+            crate::progress!("Preflight for {} failed: {}", accession, e);
+            Err(TaskError::resolving(&accession, &e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(title: &str, srr: &str, library_name: &str) -> GeoRun {
+        GeoRun {
+            gsm: "GSM1".to_owned(),
+            title: title.to_owned(),
+            srr: srr.to_owned(),
+            library_name: library_name.to_owned(),
+            counts: None,
+            paired: None,
+        }
+    }
+
+    #[test]
+    fn names_follow_the_line_arguments() {
+        let runs = vec![
+            run("liver rep 1", "SRR1", "lib1"),
+            run("liver rep 1", "SRR2", "lib2"),
+        ];
+        let names = |arguments: &[&str]| {
+            let arguments = arguments.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+            name_runs(runs.clone(), &arguments)
+                .map(|runs| runs.into_iter().map(|run| run.name).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            names(&[]).unwrap(),
+            ["liver_rep_1_SRR1", "liver_rep_1_SRR2"]
+        );
+        assert_eq!(names(&["srr_override"]).unwrap(), ["SRR1", "SRR2"]);
+        assert_eq!(names(&["library_name_override"]).unwrap(), ["lib1", "lib2"]);
+        assert!(matches!(
+            name_runs(
+                vec![run("liver", "SRR3", "")],
+                &["library_name_override".to_owned()]
+            ),
+            Err(ReadMetaError::MissingField { .. })
+        ));
+    }
+}
@@ -0,0 +1,610 @@
+//! Grammar of the download list.
+//!
+//! ```text
+//! list      = { line "\n" }
+//! line      = blank | comment | download | directory
+//! comment   = "#" { any character }
+//! download  = accession { argument }
+//! accession = run-prefix range                     SRR, ERR, DRR
+//!           | experiment-prefix range              SRX, ERX, DRX, SRP, ERP, DRP
+//!           | ("PRJNA" | "SAMN") digits
+//!           | ("GSE" | "GSM") digits
+//!           | ("HRA" | "HRX" | "HRR") digits        under controlled access, skipped
+//!           | "CRA" digits [ "CRX" range ]
+//!           | ("PRJCA" | "SAMC") digits
+//! range     = item { "," item }
+//! item      = [ "!" ] digits [ "-" digits ]        "!" leaves the numbers out
+//! argument  = "preflight" | "srr_override" | "library_name_override"
+//!           | "keep_interleaved" | "ncbi" | "ena" | "ddbj"
+//! directory = path                                  a single token holding "/" or "\"
+//! ```
+//!
+//! A token shaped like an accession, upper case letters followed by a digit,
+//! starting with a known prefix in any case, or cut short within a known prefix,
+//! is never taken as a directory. Neither is a token without a path separator,
+//! write `data/` rather than `data`. This way a typo does not turn into a directory
+//! of its own.
+//!
+//! A range like `SRX100-105,110,!103` expands in the order it is written,
+//! ascending within every item. Numbers written with leading zeros keep their width,
+//! `SRX0099-0101` expands to `SRX0099`, `SRX0100` and `SRX0101`.
+
+use std::collections::HashSet;
+
+use crate::cnbi::CONTROLLED_PREFIXES;
+
+/// Run accessions of the INSDC archives, all of which are mirrored by each other.
+const RUN_PREFIXES: [&str; 3] = ["SRR", "ERR", "DRR"];
+/// Experiments of SRA, ENA and DDBJ, which expand to their runs.
+const EXPERIMENT_PREFIXES: [&str; 3] = ["SRX", "ERX", "DRX"];
+/// Studies of SRA, ENA and DDBJ, which expand to their runs.
+const STUDY_PREFIXES: [&str; 3] = ["SRP", "ERP", "DRP"];
+/// BioProjects and BioSamples resolved through the SRA mirrors.
+const BIO_PREFIXES: [&str; 2] = ["PRJNA", "SAMN"];
+const GEO_PREFIXES: [&str; 2] = ["GSE", "GSM"];
+/// Accessions of CNCB which expand to their experiments.
+const GSA_PREFIXES: [&str; 3] = ["CRA", "PRJCA", "SAMC"];
+/// Upper bound of the accessions a single range expands to, so that a typo
+/// does not hold up the list with millions of accessions.
+const MAX_ACCESSIONS: usize = 100_000;
+const ARGUMENTS: [&str; 7] = [
+    "preflight",
+    "srr_override",
+    "library_name_override",
+    "keep_interleaved",
+    "ncbi",
+    "ena",
+    "ddbj",
+];
+
+/// A line of the list holding something to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// Line number, starting from 1.
+    pub number: usize,
+    pub text: String,
+    pub entry: Entry,
+    pub arguments: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Runs of SRA, ENA or DDBJ, downloaded under their own accession.
+    Runs(Vec<String>),
+    /// Experiments, studies, BioProjects or BioSamples resolved to their runs.
+    Accessions(Vec<String>),
+    /// A GEO series or sample.
+    Geo(String),
+    /// An accession of GSA-Human, which cannot be downloaded anonymously.
+    Controlled(String),
+    /// A CRA, PRJCA or SAMC, optionally narrowed down to some experiments of a CRA.
+    Gsa {
+        accession: String,
+        experiments: Option<Vec<String>>,
+    },
+    /// Files of the lines below are saved to this directory.
+    Directory(String),
+}
+
+/// A problem found in the list, pointing at where it starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Column in characters, starting from 1.
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// Looks like an accession, but of no archive we know.
+    UnknownPrefix(String),
+    /// A known prefix written in lower case.
+    LowerCasePrefix(String),
+    /// The number of the accession cannot be read.
+    Number {
+        accession: String,
+        error: ParseNumberError,
+    },
+    UnknownArgument(String),
+    /// Only a CRA can be narrowed down to some of its experiments.
+    UnexpectedExperiments(String),
+    /// Stops within a known prefix, e.g. `SR`.
+    IncompleteAccession(String),
+    /// Neither an accession nor a path.
+    NotAPath(String),
+    /// A directory line holds more than a single path.
+    TrailingText(String),
+}
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownPrefix(token) => write!(f, "{} is not a known accession", token),
+            Self::LowerCasePrefix(token) => {
+                write!(f, "{} is an accession and must be upper case", token)
+            }
+            Self::Number { accession, error } => {
+                write!(f, "cannot read the number of {}: {}", accession, error)
+            }
+            Self::UnknownArgument(argument) => write!(
+                f,
+                "unknown argument {}, expected one of {}",
+                argument,
+                ARGUMENTS.join(", ")
+            ),
+            Self::UnexpectedExperiments(accession) => {
+                write!(f, "only a CRA can be narrowed down to {}", accession)
+            }
+            Self::IncompleteAccession(token) => {
+                write!(f, "{} is the start of an accession without a number", token)
+            }
+            Self::NotAPath(token) => write!(
+                f,
+                "{} is not an accession, directories are written with a separator like {}/",
+                token, token
+            ),
+            Self::TrailingText(token) => write!(
+                f,
+                "{} follows a directory, which must be a single path",
+                token
+            ),
+        }
+    }
+}
+
+/// Parse the whole list, collecting every problem before anything is done.
+pub fn parse(list: &str) -> Result<Vec<Line>, Vec<ParseError>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (index, text) in list.lines().enumerate() {
+        match parse_line(index + 1, text) {
+            Ok(Some(line)) => lines.push(line),
+            Ok(None) => {}
+            Err(e) => errors.extend(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse a single line, `None` when it is blank or a comment.
+fn parse_line(number: usize, text: &str) -> Result<Option<Line>, Vec<ParseError>> {
+    let mut tokens = tokenize(text).peekable();
+    let Some((column, first)) = tokens.next() else {
+        return Ok(None);
+    };
+    if first.starts_with('#') {
+        return Ok(None);
+    }
+    let error = |column: usize, kind: ParseErrorKind| ParseError {
+        line: number,
+        column,
+        kind,
+    };
+    let line = |entry: Entry, arguments: Vec<String>| Line {
+        number,
+        text: text.to_owned(),
+        entry,
+        arguments,
+    };
+    let Some(prefix) = prefix_of(first) else {
+        if let Some(kind) = misspelled_accession(first) {
+            return Err(vec![error(column, kind)]);
+        }
+        if !first.contains(['/', '\\']) {
+            return Err(vec![error(
+                column,
+                ParseErrorKind::NotAPath(first.to_owned()),
+            )]);
+        }
+        if let Some((column, token)) = tokens.next() {
+            return Err(vec![error(
+                column,
+                ParseErrorKind::TrailingText(token.to_owned()),
+            )]);
+        }
+        return Ok(Some(line(Entry::Directory(first.to_owned()), Vec::new())));
+    };
+    let mut errors = Vec::new();
+    let mut entry = read_entry(prefix, first)
+        .map_err(|e| {
+            errors.push(error(
+                column,
+                ParseErrorKind::Number {
+                    accession: first.to_owned(),
+                    error: e,
+                },
+            ))
+        })
+        .ok();
+    if let Some((column, crx)) = tokens.next_if(|(_, token)| token.starts_with("CRX")) {
+        match entry {
+            Some(Entry::Gsa {
+                ref accession,
+                ref mut experiments,
+            }) if accession.starts_with("CRA") => match accessions_in_range("CRX", &crx[3..], 6) {
+                Ok(v) => *experiments = Some(v),
+                Err(e) => errors.push(error(
+                    column,
+                    ParseErrorKind::Number {
+                        accession: crx.to_owned(),
+                        error: e,
+                    },
+                )),
+            },
+            // The accession is already reported
+            None => {}
+            _ => errors.push(error(
+                column,
+                ParseErrorKind::UnexpectedExperiments(crx.to_owned()),
+            )),
+        }
+    }
+    let mut arguments = Vec::new();
+    for (column, argument) in tokens {
+        if ARGUMENTS.contains(&argument) {
+            arguments.push(argument.to_owned());
+        } else {
+            errors.push(error(
+                column,
+                ParseErrorKind::UnknownArgument(argument.to_owned()),
+            ));
+        }
+    }
+    match entry {
+        Some(entry) if errors.is_empty() => Ok(Some(line(entry, arguments))),
+        _ => Err(errors),
+    }
+}
+
+/// Every prefix an accession of the list may start with.
+fn prefixes() -> impl Iterator<Item = &'static str> {
+    RUN_PREFIXES
+        .into_iter()
+        .chain(EXPERIMENT_PREFIXES)
+        .chain(STUDY_PREFIXES)
+        .chain(BIO_PREFIXES)
+        .chain(GEO_PREFIXES)
+        .chain(CONTROLLED_PREFIXES)
+        .chain(GSA_PREFIXES)
+}
+
+fn prefix_of(token: &str) -> Option<&'static str> {
+    prefixes().find(|prefix| token.starts_with(prefix))
+}
+
+/// Why a token that is not an accession still cannot be a directory.
+fn misspelled_accession(token: &str) -> Option<ParseErrorKind> {
+    let upper = token.to_ascii_uppercase();
+    let followed_by_digit = |prefix: &str| {
+        upper
+            .as_bytes()
+            .get(prefix.len())
+            .is_some_and(u8::is_ascii_digit)
+    };
+    if prefixes().any(|prefix| prefix.starts_with(&upper)) {
+        return Some(ParseErrorKind::IncompleteAccession(token.to_owned()));
+    }
+    if prefixes().any(|prefix| upper.starts_with(prefix) && followed_by_digit(prefix)) {
+        return Some(ParseErrorKind::LowerCasePrefix(token.to_owned()));
+    }
+    let letters = token.bytes().take_while(u8::is_ascii_uppercase).count();
+    if letters >= 2 && followed_by_digit(&token[..letters]) {
+        return Some(ParseErrorKind::UnknownPrefix(token.to_owned()));
+    }
+    None
+}
+
+/// Read the accession starting with `prefix`.
+fn read_entry(prefix: &'static str, accession: &str) -> Result<Entry, ParseNumberError> {
+    let number = &accession[prefix.len()..];
+    if RUN_PREFIXES.contains(&prefix) {
+        return accessions_in_range(prefix, number, 0).map(Entry::Runs);
+    }
+    if EXPERIMENT_PREFIXES.contains(&prefix) || STUDY_PREFIXES.contains(&prefix) {
+        return accessions_in_range(prefix, number, 0).map(Entry::Accessions);
+    }
+    read_single(number)?;
+    let accession = match prefix {
+        // GSA pages are found under the padded project, as are the experiments.
+        "CRA" => format!("CRA{:0>6}", number),
+        _ => accession.to_owned(),
+    };
+    Ok(if BIO_PREFIXES.contains(&prefix) {
+        Entry::Accessions(vec![accession])
+    } else if GEO_PREFIXES.contains(&prefix) {
+        Entry::Geo(accession)
+    } else if crate::cnbi::is_controlled_accession(&accession) {
+        Entry::Controlled(accession)
+    } else {
+        Entry::Gsa {
+            accession,
+            experiments: None,
+        }
+    })
+}
+
+/// Every accession of a range, with the number padded by zeros to at least `width`.
+fn accessions_in_range(
+    prefix: &str,
+    range: &str,
+    width: usize,
+) -> Result<Vec<String>, ParseNumberError> {
+    Ok(read_numbers(range)?
+        .into_iter()
+        .map(|(number, written)| {
+            format!("{}{:0>width$}", prefix, number, width = width.max(written))
+        })
+        .collect())
+}
+
+/// Read the numbers of a range from syntax `123456-789000,789005,!789001`,
+/// each with the width it is padded to, `0` when it is not.
+/// The ends of a range can be supplied with decending order.
+fn read_numbers(range: &str) -> Result<Vec<(usize, usize)>, ParseNumberError> {
+    let mut numbers: Vec<(usize, usize)> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut excluded = Vec::new();
+    for item in range.split(',') {
+        let (exclude, item) = match item.strip_prefix('!') {
+            Some(item) => (true, item),
+            None => (false, item),
+        };
+        let (from, to, width) = read_item(item)?;
+        if exclude {
+            excluded.push((from, to));
+            continue;
+        }
+        if to - from >= MAX_ACCESSIONS - numbers.len() {
+            return Err(ParseNumberError::TooMany);
+        }
+        for number in from..=to {
+            if seen.insert(number) {
+                numbers.push((number, width));
+            }
+        }
+    }
+    numbers.retain(|(number, _)| {
+        !excluded
+            .iter()
+            .any(|(from, to)| (from..=to).contains(&number))
+    });
+    if numbers.is_empty() {
+        return Err(ParseNumberError::NothingLeft);
+    }
+    Ok(numbers)
+}
+
+/// Read a single number or range `123456-789000` of a range,
+/// returning both ends and the width of the padded numbers.
+fn read_item(item: &str) -> Result<(usize, usize, usize), ParseNumberError> {
+    let (num1, num2) = item.split_once('-').unwrap_or((item, item));
+    let width = [num1, num2]
+        .into_iter()
+        .filter(|number| number.len() > 1 && number.starts_with('0'))
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let num1 = read_single(num1)?;
+    let num2 = read_single(num2)?;
+    // The order of the number is automatically determined
+    Ok((num1.min(num2), num1.max(num2), width))
+}
+
+fn read_single(number: &str) -> Result<usize, ParseNumberError> {
+    if number.is_empty() {
+        return Err(ParseNumberError::IncompleteRange);
+    }
+    if !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ParseNumberError::IllegalCharacter);
+    }
+    number.parse().map_err(|_| ParseNumberError::TooLarge)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseNumberError {
+    IllegalCharacter,
+    IncompleteRange,
+    TooLarge,
+    /// The range holds more than `MAX_ACCESSIONS` numbers.
+    TooMany,
+    /// Every number of the range is left out.
+    NothingLeft,
+}
+impl std::fmt::Display for ParseNumberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IllegalCharacter => f.write_str("Illegal character found"),
+            Self::IncompleteRange => f.write_str("Incomplete range"),
+            Self::TooLarge => f.write_str("Number too large"),
+            Self::TooMany => write!(f, "More than {} accessions in the range", MAX_ACCESSIONS),
+            Self::NothingLeft => f.write_str("Every number is left out"),
+        }
+    }
+}
+
+/// Split a line into its tokens with the column they start at.
+fn tokenize(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut column = 0;
+    let mut start = None;
+    let mut tokens = Vec::new();
+    for (offset, char) in text.char_indices() {
+        column += 1;
+        match (char.is_whitespace(), start) {
+            (false, None) => start = Some((column, offset)),
+            (true, Some((start_column, start_offset))) => {
+                tokens.push((start_column, &text[start_offset..offset]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((start_column, start_offset)) = start {
+        tokens.push((start_column, &text[start_offset..]));
+    }
+    tokens.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_kind_of_line() {
+        let list = "# comment\n\nSRR10-8 ena\nSRX5\nPRJNA1 srr_override\nGSE2\n\
+                    HRA3\nCRA4 CRX12-13 preflight\nSAMC5\nout/batch\n";
+        let entries = parse(list)
+            .unwrap()
+            .into_iter()
+            .map(|line| (line.number, line.entry, line.arguments))
+            .collect::<Vec<_>>();
+        let runs = ["SRR8", "SRR9", "SRR10"].map(String::from).to_vec();
+        assert_eq!(entries[0], (3, Entry::Runs(runs), vec!["ena".to_owned()]));
+        assert_eq!(entries[1].1, Entry::Accessions(vec!["SRX5".to_owned()]));
+        assert_eq!(entries[2].1, Entry::Accessions(vec!["PRJNA1".to_owned()]));
+        assert_eq!(entries[3].1, Entry::Geo("GSE2".to_owned()));
+        assert_eq!(entries[4].1, Entry::Controlled("HRA3".to_owned()));
+        assert_eq!(
+            entries[5].1,
+            Entry::Gsa {
+                accession: "CRA000004".to_owned(),
+                experiments: Some(vec!["CRX000012".to_owned(), "CRX000013".to_owned()]),
+            }
+        );
+        assert_eq!(
+            entries[6].1,
+            Entry::Gsa {
+                accession: "SAMC5".to_owned(),
+                experiments: None,
+            }
+        );
+        assert_eq!(entries[7].1, Entry::Directory("out/batch".to_owned()));
+    }
+
+    #[test]
+    fn pads_gsa_projects_like_their_experiments() {
+        let entries = parse("CRA4538 CRX264201-264202\nCRA004538\nCRA0004538\n").unwrap();
+        let accessions = entries
+            .iter()
+            .map(|line| match &line.entry {
+                Entry::Gsa { accession, .. } => accession.as_str(),
+                entry => panic!("unexpected {:?}", entry),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(accessions, ["CRA004538", "CRA004538", "CRA0004538"]);
+        assert_eq!(
+            entries[0].entry,
+            Entry::Gsa {
+                accession: "CRA004538".to_owned(),
+                experiments: Some(vec!["CRX264201".to_owned(), "CRX264202".to_owned()]),
+            }
+        );
+    }
+
+    #[test]
+    fn expands_lists_of_ranges() {
+        let accessions = |range: &str| accessions_in_range("SRX", range, 0);
+        assert_eq!(
+            accessions("100-102,110,122-120,!101,!121").unwrap(),
+            ["SRX100", "SRX102", "SRX110", "SRX120", "SRX122"]
+        );
+        assert_eq!(
+            accessions("0012345,0099-101").unwrap(),
+            ["SRX0012345", "SRX0099", "SRX0100", "SRX0101"]
+        );
+        assert_eq!(
+            accessions_in_range("CRX", "12,0000013", 6).unwrap(),
+            ["CRX000012", "CRX0000013"]
+        );
+        assert_eq!(accessions("5,5,!6").unwrap(), ["SRX5"]);
+        assert_eq!(accessions("1,,2"), Err(ParseNumberError::IncompleteRange));
+        assert_eq!(accessions("1-2-3"), Err(ParseNumberError::IllegalCharacter));
+        assert_eq!(accessions("!1"), Err(ParseNumberError::NothingLeft));
+        assert_eq!(accessions("1-9999999"), Err(ParseNumberError::TooMany));
+        assert_eq!(
+            accessions("99999999999999999999"),
+            Err(ParseNumberError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn directory_is_a_single_path() {
+        let lines = parse("  data/run 1\n").unwrap_err();
+        assert_eq!(
+            lines,
+            vec![ParseError {
+                line: 1,
+                column: 12,
+                kind: ParseErrorKind::TrailingText("1".to_owned()),
+            }]
+        );
+        let lines = parse("data/run_1\nbatch/\nC:\\data\n").unwrap();
+        assert_eq!(lines[0].entry, Entry::Directory("data/run_1".to_owned()));
+        assert_eq!(lines[1].entry, Entry::Directory("batch/".to_owned()));
+        assert_eq!(lines[2].entry, Entry::Directory("C:\\data".to_owned()));
+        let errors = parse("batch\nprj\n").unwrap_err();
+        assert_eq!(errors[0].kind, ParseErrorKind::NotAPath("batch".to_owned()));
+        assert_eq!(
+            errors[1].kind,
+            ParseErrorKind::IncompleteAccession("prj".to_owned())
+        );
+    }
+
+    #[test]
+    fn reports_every_error_with_its_position() {
+        let list = "SRR1\nSXR123\nsrr5\nSRX1-\nSR\né\nGSE12a\nCRA1 CRX2 presflight\nPRJCA1 CRX1\n";
+        let errors = parse(list)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.line, e.column, e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (2, 1, ParseErrorKind::UnknownPrefix("SXR123".to_owned())),
+                (3, 1, ParseErrorKind::LowerCasePrefix("srr5".to_owned())),
+                (
+                    4,
+                    1,
+                    ParseErrorKind::Number {
+                        accession: "SRX1-".to_owned(),
+                        error: ParseNumberError::IncompleteRange,
+                    }
+                ),
+                (5, 1, ParseErrorKind::IncompleteAccession("SR".to_owned())),
+                (6, 1, ParseErrorKind::NotAPath("é".to_owned())),
+                (
+                    7,
+                    1,
+                    ParseErrorKind::Number {
+                        accession: "GSE12a".to_owned(),
+                        error: ParseNumberError::IllegalCharacter,
+                    }
+                ),
+                (
+                    8,
+                    11,
+                    ParseErrorKind::UnknownArgument("presflight".to_owned())
+                ),
+                (
+                    9,
+                    8,
+                    ParseErrorKind::UnexpectedExperiments("CRX1".to_owned())
+                ),
+            ]
+        );
+    }
+}
@@ -1,19 +1,37 @@
+/// Print a line of progress, which goes to stderr
+/// while stdout is kept for the table of `resolve`.
+#[macro_export]
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if $crate::PROGRESS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Run `$($tt)+.await` until it succeeds, the error is not retryable
+/// or the retries of the policy are used up, sleeping between attempts.
+/// Evaluates to the last result.
 #[macro_export]
 macro_rules! with_retry {
-    ($retry:expr=>$($tt:tt)+) => {
+    ($policy:expr=>$($tt:tt)+) => {
         {
-            let mut retry_times = $retry;
+            use $crate::retry::Retryable;
+            let policy: $crate::retry::RetryPolicy = $policy;
+            let mut attempt = 0;
             loop {
                 match $($tt)+.await{
                     Ok(v) => break Ok(v),
-                    Err(_) => {
-                        retry_times += 1;
-                        std::thread::sleep(std::time::Duration::from_millis(20));
+                    Err(e) => {
+                        attempt += 1;
+                        if !e.is_retryable() || attempt > policy.max_retries {
+                            break Err(e);
+                        }
+                        tokio::time::sleep(policy.delay(attempt)).await;
                     }
                 }
-                if retry_times >= $retry {
-                    break Err(());
-                }
             }
         }
     };
@@ -25,26 +43,44 @@ macro_rules! join_task {
         $($task:tt)+
     }
     ) => {
-        {   
+        {
             use tokio::task::JoinSet;
             let mut join_set = JoinSet::new();
             for $ident in $iterable.clone(){
+                let item = $ident.clone();
                 let task = {$($task)+};
-                join_set.spawn(task);
+                join_set.spawn(async move { (item, task.await) });
             }
             let total = $iterable.len();
             let mut success = 0;
+            let mut controlled_access = Vec::new();
+            let mut invalid = Vec::new();
             while let Some(result) = join_set.join_next().await{
-                if let Err(e)=result{
-                    println!("Internal Error: Cannot read join result: {:?}",e);
-                    continue;
-                }
-                if let Ok(Ok(ok_result)) = result{
-                    success += 1;
-                    $iterable.retain(|v|*v != ok_result.0);
+                match result{
+                    Err(e) => $crate::progress!("Internal Error: Cannot read join result: {:?}",e),
+                    Ok((_, Ok(ok_result))) => {
+                        success += 1;
+                        $iterable.retain(|v|*v != ok_result.0);
+                    }
+                    // Not a failure, running again will not change it
+                    Ok((item, Err($crate::TaskError::ControlledAccess(accession)))) => {
+                        $iterable.retain(|v|*v != item);
+                        $crate::report_controlled_access(accession.clone());
+                        controlled_access.push(accession);
+                    }
+                    Ok((_, Err($crate::TaskError::Invalid(file)))) => {
+                        invalid.push(file);
+                    }
+                    Ok((_, Err($crate::TaskError::Failed))) => {}
                 }
             }
-            println!("{} out of {} succeeded, failed tasks: {:?}",success,total,$iterable)
+            $crate::progress!("{} out of {} succeeded, failed tasks: {:?}",success,total,$iterable);
+            if !controlled_access.is_empty(){
+                $crate::progress!("Under controlled access, not retried: {:?}",controlled_access);
+            }
+            if !invalid.is_empty(){
+                $crate::progress!("Failed validation, retryable: {:?}",invalid);
+            }
         }
     };
 }
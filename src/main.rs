@@ -1,303 +1,1090 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use bytes::Bytes;
-use reqwest::{Client, Method, Request, Url};
+use clap::Parser;
+use flate2::write::MultiGzDecoder;
+use md5::{Digest, Md5};
+use reqwest::{
+    header::{HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    Client, Method, Request, Response, StatusCode, Url,
+};
 use serde::{Deserialize, Serialize};
-use tokio::{fs, sync::Semaphore};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::{mpsc, Semaphore},
+    task::{self, JoinSet},
+    time,
+};
 
-use crate::{cnbi::read_alias_and_crr_with_crx, ncbi::read_name_and_srr};
+use crate::{
+    cli::{Cli, Command, ListArgs},
+    cnbi::read_runs_with_crx,
+    fastq::{Deinterleaver, FastqStats},
+    list::{Entry, Line},
+    mirror::{Mirror, RunFile},
+    ncbi::NamedRun,
+    ratelimit::RateLimiter,
+};
 
+mod cli;
 mod cnbi;
+mod ddbj;
+mod ena;
+mod fastq;
+mod geo;
+mod list;
+mod mirror;
 mod ncbi;
+mod ratelimit;
+mod retry;
 #[macro_use]
 mod macros;
 
 #[tokio::main]
-async fn main() {
-    let global_config = match fs::read("./config.json").await {
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or_default();
+    // The table `resolve` prints to stdout is meant to be piped
+    if let Command::Resolve { output: None, .. } = command {
+        PROGRESS_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    let config_path = cli.config.display().to_string();
+    let mut global_config = match fs::read(&cli.config).await {
         Ok(v) => {
-            println!("Loading config from config.json.");
-            Arc::new(serde_json::from_slice::<Config>(&v).unwrap())
+            progress!("Loading config from {}.", config_path);
+            match serde_json::from_slice::<Config>(&v) {
+                Ok(config) => config,
+                Err(e) => {
+                    progress!("Cannot parse {}: {}", config_path, e);
+                    progress!("Exiting...");
+                    return ExitCode::FAILURE;
+                }
+            }
         }
         Err(e) => {
-            println!("Cannot find config.json: {:?}", e);
-            println!("Using default config values.");
-            Arc::new(Config::default())
+            progress!("Cannot find {}: {:?}", config_path, e);
+            progress!("Using default config values.");
+            Config::default()
         }
     };
-    let client = NetworkInstance::new(global_config.max_concurrent_requests);
-    let list = match fs::read_to_string("./download_list.txt").await {
+    cli.overrides.apply(&mut global_config);
+    if global_config.ncbi_api_key.is_none() {
+        global_config.ncbi_api_key = std::env::var("NCBI_API_KEY").ok();
+    }
+    let global_config = Arc::new(global_config);
+    let (list_args, mode, output) = match command {
+        Command::Download(list_args) => (list_args, Mode::Download, None),
+        Command::Preflight(list_args) => (list_args, Mode::Preflight, None),
+        Command::Resolve { list, output } => (list, Mode::Resolve, output),
+        Command::Check(list_args) => return check_list(list_args).await,
+        Command::Verify { out_dir, files } => return verify_files(out_dir, files).await,
+    };
+    // Nothing is done before the whole list is known to be fine
+    let Some(lines) = read_list(&list_args.list).await else {
+        progress!("Exiting...");
+        return ExitCode::FAILURE;
+    };
+    let out_dir = list_args.out_dir.display().to_string().replace('\\', "/");
+    if mode == Mode::Download {
+        if let Err(e) = fs::create_dir_all(&out_dir).await {
+            progress!("Cannot use {} as output directory: {:?}", out_dir, e);
+            progress!("Exiting...");
+            return ExitCode::FAILURE;
+        }
+    }
+    let client = NetworkInstance::new(&global_config);
+    let mut rows = Vec::new();
+    let failed = process_list(client, global_config, lines, &out_dir, mode, &mut rows).await;
+    if mode == Mode::Resolve {
+        let tsv = rows
+            .iter()
+            .map(|(accession, run, name)| format!("{}\t{}\t{}\n", accession, run, name))
+            .collect::<String>();
+        match output {
+            Some(output) => {
+                if let Err(e) = fs::write(&output, tsv).await {
+                    progress!("Cannot write {}: {:?}", output.display(), e);
+                    return ExitCode::FAILURE;
+                }
+                progress!("{} runs written to {}", rows.len(), output.display());
+            }
+            None => print!("{}", tsv),
+        }
+    }
+    let mut controlled = CONTROLLED_ACCESS
+        .lock()
+        .expect("lock not to be poisoned")
+        .clone();
+    controlled.sort();
+    controlled.dedup();
+    if !controlled.is_empty() {
+        progress!(
+            "{} under controlled access, an approved data access request is needed: {}",
+            controlled.len(),
+            controlled.join(", ")
+        );
+    }
+    if failed > 0 {
+        progress!("{} lines did not succeed", failed);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Whether `progress!` prints to stderr, so that stdout only holds the output.
+pub static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Accessions found to be under controlled access while working through the list,
+/// which running the list again will not change.
+static CONTROLLED_ACCESS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Keep `accession` for the summary of what is under controlled access.
+pub fn report_controlled_access(accession: String) {
+    CONTROLLED_ACCESS
+        .lock()
+        .expect("lock not to be poisoned")
+        .push(accession);
+}
+
+/// What is done with the lines of the download list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Download,
+    /// Look up what every line holds, as the `preflight` line argument does.
+    Preflight,
+    /// Collect the runs every line resolves to.
+    Resolve,
+}
+
+/// Work through the lines of the download list, saving files below `out_dir`.
+/// In resolve mode the runs are collected into `rows` as `(accession, run, file name)`.
+/// Returns the number of lines that did not succeed,
+/// lines under controlled access are reported separately.
+async fn process_list(
+    client: NetworkInstance,
+    global_config: Arc<Config>,
+    lines: Vec<Line>,
+    out_dir: &str,
+    mode: Mode,
+    rows: &mut Vec<(String, String, String)>,
+) -> usize {
+    let mut failed = 0;
+    let mut file_path = out_dir.to_owned();
+    for Line {
+        number,
+        text,
+        entry,
+        arguments,
+    } in lines
+    {
+        progress!("Line feed {}: {}", number, text);
+        let preflight = mode == Mode::Preflight || arguments.contains(&"preflight".into());
+        let succeeded = match entry {
+            // Files of bare runs are always named after the run
+            Entry::Runs(srrs) => {
+                let runs = srrs
+                    .into_iter()
+                    .map(|srr| NamedRun {
+                        name: srr.clone(),
+                        srr,
+                        counts: None,
+                        paired: None,
+                    })
+                    .collect::<Vec<_>>();
+                if mode == Mode::Resolve {
+                    rows.extend(
+                        runs.into_iter()
+                            .map(|run| (run.srr.clone(), run.srr, run.name)),
+                    );
+                    true
+                } else if preflight {
+                    progress!("preflight not available for run accessions");
+                    true
+                } else {
+                    let mirrors = Arc::new(Mirror::order(&arguments, &global_config));
+                    download_runs(
+                        client.clone(),
+                        global_config.clone(),
+                        file_path.clone(),
+                        runs,
+                        mirrors,
+                        split_pairs(&arguments),
+                    )
+                    .await
+                    .is_ok()
+                }
+            }
+            // Experiments, studies, BioProjects and BioSamples expand to their runs
+            Entry::Accessions(mut accessions) => {
+                let arguments = Arc::new(arguments);
+                if mode == Mode::Resolve {
+                    let runs = resolve_accessions(
+                        &client,
+                        global_config.as_ref(),
+                        &arguments,
+                        &mut accessions,
+                    )
+                    .await;
+                    rows.extend(
+                        runs.into_iter()
+                            .map(|(accession, run)| (accession, run.srr, run.name)),
+                    );
+                    accessions.is_empty()
+                } else if preflight {
+                    progress!("Running prelight for line {}", number);
+                    join_task!(accession in accessions=>{
+                        let client = client.clone();
+                        let global_config = global_config.clone();
+                        let arguments = arguments.clone();
+                        async move{
+                            crate::mirror::preflight(client,accession.clone(),global_config,arguments).await.map(|_|(accession,()))
+                        }
+                    });
+                    accessions.is_empty()
+                } else {
+                    let runs = resolve_accessions(
+                        &client,
+                        global_config.as_ref(),
+                        &arguments,
+                        &mut accessions,
+                    )
+                    .await
+                    .into_iter()
+                    .map(|(_, run)| run)
+                    .collect::<Vec<_>>();
+                    let mirrors = Arc::new(Mirror::order(&arguments, &global_config));
+                    let downloaded = download_runs(
+                        client.clone(),
+                        global_config.clone(),
+                        file_path.clone(),
+                        runs,
+                        mirrors,
+                        split_pairs(&arguments),
+                    )
+                    .await;
+                    downloaded.is_ok() && accessions.is_empty()
+                }
+            }
+            // GEO series and samples link to SRA runs
+            Entry::Geo(accession) => {
+                if preflight && mode != Mode::Resolve {
+                    progress!("Running prelight for {}", accession);
+                    settled(
+                        crate::geo::preflight(client.clone(), accession, global_config.clone())
+                            .await,
+                    )
+                } else {
+                    match crate::geo::read_names_and_srrs(
+                        client.clone(),
+                        &accession,
+                        global_config.as_ref(),
+                        &arguments,
+                    )
+                    .await
+                    {
+                        Ok(runs) if mode == Mode::Resolve => {
+                            rows.extend(
+                                runs.into_iter()
+                                    .map(|run| (accession.clone(), run.srr, run.name)),
+                            );
+                            true
+                        }
+                        Ok(runs) => {
+                            let mirrors = Arc::new(Mirror::order(&arguments, &global_config));
+                            download_runs(
+                                client.clone(),
+                                global_config.clone(),
+                                file_path.clone(),
+                                runs,
+                                mirrors,
+                                split_pairs(&arguments),
+                            )
+                            .await
+                            .is_ok()
+                        }
+                        Err(e) => {
+                            progress!("Cannot resolve {}: {}", accession, e);
+                            settled::<()>(Err(TaskError::resolving(&accession, &e)))
+                        }
+                    }
+                }
+            }
+            // GSA-Human needs an approved data access request
+            Entry::Controlled(accession) => {
+                progress!(
+                    "{} is under controlled access on GSA-Human and cannot be downloaded anonymously, skipped",
+                    accession
+                );
+                report_controlled_access(accession);
+                true
+            }
+            // A CRA with a range of CRX,
+            // or a whole CRA, PRJCA or SAMC which expand to their experiments
+            Entry::Gsa {
+                accession,
+                experiments,
+            } => {
+                let mut experiments = match experiments {
+                    Some(crxs) => crxs
+                        .into_iter()
+                        .map(|crx| (accession.clone(), crx))
+                        .collect::<Vec<_>>(),
+                    None => match crate::cnbi::read_experiments(
+                        client.clone(),
+                        &accession,
+                        global_config.as_ref(),
+                    )
+                    .await
+                    {
+                        Ok(v) => v,
+                        Err(e) => {
+                            progress!("Cannot resolve {}: {}", accession, e);
+                            if !settled::<()>(Err(TaskError::resolving(&accession, &e))) {
+                                failed += 1;
+                            }
+                            continue;
+                        }
+                    },
+                };
+                progress!(
+                    "{} expands to {} experiments: {}",
+                    accession,
+                    experiments.len(),
+                    experiments
+                        .iter()
+                        .map(|(cra, crx)| format!("{}/{}", cra, crx))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                if mode == Mode::Resolve {
+                    resolve_gsa_experiments(&client, global_config.as_ref(), &experiments, rows)
+                        .await
+                        .is_ok()
+                } else if preflight {
+                    join_task!(experiment in experiments=>{
+                        let client = client.clone();
+                        let config = global_config.clone();
+                        async move {
+                            crate::cnbi::preflight(
+                                client, experiment.0.clone(), experiment.1.clone(), config
+                            ).await.map(|_|(experiment,()))
+                        }
+                    });
+                    experiments.is_empty()
+                } else {
+                    download_gsa_experiments(
+                        client.clone(),
+                        global_config.clone(),
+                        file_path.clone(),
+                        experiments,
+                    )
+                    .await
+                    .is_ok()
+                }
+            }
+            // The line is only for configuration
+            Entry::Directory(directory) => {
+                // Relative directories are taken below the output directory
+                let maybe_file_path = std::path::Path::new(out_dir)
+                    .join(directory.replace('\\', "/"))
+                    .display()
+                    .to_string()
+                    .replace('\\', "/");
+                if mode == Mode::Download {
+                    if let Err(e) = fs::create_dir_all(maybe_file_path.clone()).await {
+                        progress!(
+                            "Cannot use {} as working directory: {:?}",
+                            maybe_file_path,
+                            e
+                        );
+                        progress!("Exiting...");
+                        return failed + 1;
+                    }
+                }
+                file_path = maybe_file_path;
+                true
+            }
+        };
+        if !succeeded {
+            failed += 1;
+        }
+    }
+    progress!("All lines have been read");
+    failed
+}
+
+/// Whether the work on a line is done with, which it is when the line
+/// turns out to be under controlled access. That is reported instead.
+fn settled<T>(result: Result<T, TaskError>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(TaskError::ControlledAccess(accession)) => {
+            report_controlled_access(accession);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Read and parse the download list, telling every problem found in it.
+async fn read_list(path: &std::path::Path) -> Option<Vec<Line>> {
+    let list = match fs::read_to_string(path).await {
         Ok(v) => {
-            println!("Loading download list from download_list.txt");
+            progress!("Loading download list from {}", path.display());
             v
         }
         Err(e) => {
-            println!("Cannot read download.txt: {:?}", e);
-            println!("Exiting...");
-            return;
+            progress!("Cannot read {}: {:?}", path.display(), e);
+            return None;
         }
     };
-    let mut lines = list.lines();
-    let mut file_path = "".to_string();
-    // Start reading lines
-    while let Some(item) = lines.next() {
-        print!("Line feed: {}", item);
-        if item.is_empty() || item[0..1] == "#".to_owned() {
-            print!("...Skipped\n");
-            continue; // Skip the empty lines or commented out lines
-        }
-        print!("\n");
-        // When the line is started with SRR
-        if item[..3] == "SRR".to_string() {
-            let mut download_line = item.split_whitespace(); // read the line with space as separator
-            let maybe_srr = &download_line.next().expect("Found first element")[3..];
-            let (from, to) = match read_number(maybe_srr) {
-                Ok(v) => v,
-                Err(e) => {
-                    println!("Failed to parse SRR number {}: {}", maybe_srr, e);
+    match crate::list::parse(&list) {
+        Ok(lines) => Some(lines),
+        Err(errors) => {
+            for e in errors.iter() {
+                progress!("{}: {}", path.display(), e);
+            }
+            progress!("{} problems found in {}", errors.len(), path.display());
+            None
+        }
+    }
+}
+
+/// Parse the download list without touching the network.
+async fn check_list(list_args: ListArgs) -> ExitCode {
+    let Some(lines) = read_list(&list_args.list).await else {
+        return ExitCode::FAILURE;
+    };
+    progress!(
+        "{} lines to work through in {}",
+        lines.len(),
+        list_args.list.display()
+    );
+    ExitCode::SUCCESS
+}
+
+/// Resolve GSA experiments to the files of their runs, collected into `rows`
+/// as `(experiment, run, file name)`.
+async fn resolve_gsa_experiments(
+    client: &NetworkInstance,
+    config: &Config,
+    experiments: &[(String, String)],
+    rows: &mut Vec<(String, String, String)>,
+) -> Result<(), ()> {
+    for (cra, crx) in experiments {
+        let runs = match read_runs_with_crx(client.clone(), cra, crx, config).await {
+            Ok(v) => v,
+            Err(e) => {
+                progress!("Cannot resolve {}/{}: {}", cra, crx, e);
+                if settled::<()>(Err(TaskError::resolving(crx, &e))) {
                     continue;
                 }
-            }; // get the number
-            let arguments = download_line
-                .map(|str| str.to_string())
-                .collect::<Vec<String>>();
-            if arguments.contains(&"preflight".into()) {
-                println!("preflight not available for SRR numbers");
-                continue;
+                return Err(());
             }
-            let mut srr_list = Vec::from_iter(from..(to + 1));
-            join_task!(srr in srr_list=>{
-                let client = client.clone();
-                let file_path = file_path.clone();
-                let config = global_config.clone();
-                async move {download(
-                client,
-                Url::from_str(&format!(
-                    "https://www.be-md.ncbi.nlm.nih.gov/Traces/sra-reads-be/fastq?acc=SRR{}",
-                    srr
-                ))
-                .unwrap(),
-                config.as_ref(),
-                file_path,
-            ).await.map(|_|(srr,()))}
-            });
-            continue;
+        };
+        // Files are named in the order of the runs and their files
+        let crrs = runs
+            .iter()
+            .flat_map(|run| run.files.iter().map(|_| run.crr.clone()));
+        for ((name, _), crr) in crate::cnbi::file_names(&runs).into_iter().zip(crrs) {
+            rows.push((crx.clone(), crr, name));
         }
-        // When the line is started with SRX
-        if item[..3] == "SRX".to_string() {
-            let mut item_line = item.split(" ");
-            let srx = item_line.next().unwrap();
-            let (from, to) = match read_number(srx) {
+    }
+    Ok(())
+}
+
+/// Validate the given `.fastq.gz` files, or every one found below `out_dir`.
+async fn verify_files(out_dir: PathBuf, mut files: Vec<PathBuf>) -> ExitCode {
+    if files.is_empty() {
+        let mut directories = vec![out_dir];
+        while let Some(directory) = directories.pop() {
+            let mut entries = match fs::read_dir(&directory).await {
                 Ok(v) => v,
                 Err(e) => {
-                    println!("Error when reading SRX number {} : {:?}", srx, e);
-                    continue;
+                    progress!("Cannot read {}: {:?}", directory.display(), e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else if crate::fastq::is_fastq_gz(&path.display().to_string()) {
+                    files.push(path);
                 }
+            }
+        }
+        files.sort();
+    }
+    let mut failed = 0;
+    for file in files.iter() {
+        let file_path = file.display().to_string();
+        let path = file_path.clone();
+        let result = task::spawn_blocking(move || crate::fastq::validate_file(&path))
+            .await
+            .expect("validation not to panic");
+        match result {
+            Ok(stats) => progress!(
+                "{} is valid with {} reads, {} bases",
+                file_path,
+                stats.reads,
+                stats.bases
+            ),
+            Err(e) => {
+                progress!("{} failed validation: {}", file_path, e);
+                failed += 1;
+            }
+        }
+    }
+    progress!(
+        "{} out of {} files are valid",
+        files.len() - failed,
+        files.len()
+    );
+    if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// To initate a download, the file path to write to and the SRR number is needed.
+/// The response body is streamed to the file chunk by chunk, so memory use
+/// does not grow with the size of the file.
+/// When `expected_md5` is given, a file with another MD5 is removed and downloaded again.
+/// With `validate` set, a `.fastq.gz` is checked and its reads are counted;
+/// one that fails validation is removed and the download counts as failed.
+/// A file the server denies access to fails with [`TaskError::ControlledAccess`].
+async fn download(
+    client: NetworkInstance,
+    url: Url,
+    config: &Config,
+    file_path: String,
+    expected_md5: Option<&str>,
+    validate: bool,
+) -> Result<Option<FastqStats>, TaskError> {
+    let result = crate::with_retry!(
+        config.into() =>
+        async {
+            let downloaded = if config.segments > 1 {
+                client
+                    .get_to_file_segmented(
+                        url.clone(),
+                        config.download_timeout,
+                        &file_path,
+                        config.segments,
+                        config.min_segment_size,
+                    )
+                    .await?
+            } else {
+                client
+                    .get_to_file(url.clone(), config.download_timeout, &file_path)
+                    .await?
             };
-            let file_path = file_path.clone();
-            // arguments are supplied after the SRX number
-            let arguments = Arc::new(
-                item_line
-                    .map(|str| str.to_string())
-                    .collect::<Vec<String>>(),
+            match expected_md5 {
+                Some(expected) if !expected.eq_ignore_ascii_case(&downloaded.md5) => {
+                    fs::remove_file(&file_path).await?;
+                    Err(GetReqError::ChecksumMismatch {
+                        expected: expected.to_owned(),
+                        actual: downloaded.md5,
+                    })
+                }
+                _ => Ok(downloaded),
+            }
+        }
+    );
+    if let Err(e) = result {
+        progress!("Failed to download {}: {}", url, e);
+        return Err(match e {
+            GetReqError::Forbidden(_) => TaskError::ControlledAccess(file_path),
+            _ => TaskError::Failed,
+        });
+    }
+    match expected_md5 {
+        Some(md5) => progress!(
+            "{} finished and written to {}, MD5 {} verified",
+            url,
+            file_path,
+            md5
+        ),
+        None => progress!("{} finished and written to {}", url, file_path),
+    }
+    if !validate || !crate::fastq::is_fastq_gz(&file_path) {
+        return Ok(None);
+    }
+    validate_download(file_path).await.map(Some)
+}
+
+/// Download an interleaved `.fastq.gz` of a paired run and split it into
+/// one file per mate on the way, validating both when `validate` is set.
+async fn download_paired(
+    client: NetworkInstance,
+    url: Url,
+    config: &Config,
+    file_paths: [String; 2],
+    validate: bool,
+) -> Result<Option<FastqStats>, TaskError> {
+    let result = crate::with_retry!(
+        config.into() =>
+        client.get_to_paired_files(
+            url.clone(),
+            config.download_timeout,
+            [&file_paths[0], &file_paths[1]],
+        )
+    );
+    if let Err(e) = result {
+        progress!("Failed to download {}: {}", url, e);
+        return Err(TaskError::Failed);
+    }
+    progress!(
+        "{} finished and split into {} and {}",
+        url,
+        file_paths[0],
+        file_paths[1]
+    );
+    if !validate {
+        return Ok(None);
+    }
+    let mut stats = FastqStats::default();
+    for (index, file_path) in file_paths.iter().enumerate() {
+        match validate_download(file_path.clone()).await {
+            Ok(mate) => stats = stats + mate,
+            Err(e) => {
+                // The mates are only of use together.
+                remove_files(&file_paths[..index]).await;
+                remove_files(&file_paths[index + 1..]).await;
+                return Err(e);
+            }
+        }
+    }
+    Ok(Some(stats))
+}
+
+/// Check a downloaded `.fastq.gz` and count its reads.
+/// A file that fails validation is removed.
+async fn validate_download(file_path: String) -> Result<FastqStats, TaskError> {
+    let path = file_path.clone();
+    let result = task::spawn_blocking(move || crate::fastq::validate_file(&path))
+        .await
+        .expect("validation not to panic");
+    match result {
+        Ok(stats) => {
+            progress!(
+                "{} is valid with {} reads, {} bases",
+                file_path,
+                stats.reads,
+                stats.bases
             );
-            // Set up preflight
-            if arguments.contains(&"preflight".into()) {
-                println!("Running prelight for SRX{}-{}", from, to);
-                let mut srx_list = Vec::from_iter(from..(to + 1));
-                join_task!(srx in srx_list=>{
-                    let client = client.clone();
-                    let global_config = global_config.clone();
-                    let arguments = arguments.clone();
-                    async move{
-                        crate::ncbi::preflight_srx(client,srx,global_config,arguments).await.map(|_|(srx,()))
+            Ok(stats)
+        }
+        Err(e) => {
+            progress!("{} failed validation: {}", file_path, e);
+            remove_files(std::slice::from_ref(&file_path)).await;
+            Err(TaskError::Invalid(file_path))
+        }
+    }
+}
+
+/// Remove downloaded files that turned out to be unusable.
+async fn remove_files(file_paths: &[String]) {
+    for file_path in file_paths {
+        if let Err(e) = fs::remove_file(file_path).await {
+            progress!("Cannot remove {}: {}", file_path, e);
+        }
+    }
+}
+
+/// Remove whatever was written for the given files, complete or not,
+/// including the part files of its segments.
+async fn discard_files(file_paths: &[String]) {
+    for file_path in file_paths {
+        for path in [file_path.clone(), format!("{}.part", file_path)] {
+            match fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    progress!("Cannot remove {}: {}", path, e)
+                }
+                _ => {}
+            }
+        }
+        remove_stale_segments(file_path, None).await;
+    }
+}
+
+/// Resolve every SRA accession of a line to its runs, paired with the accession
+/// they were found under. Accessions that cannot be resolved are left in `accessions`,
+/// those under controlled access are reported instead.
+/// Runs of different accessions may share a sample, so names are made unique
+/// across the whole line and a run found twice is kept once.
+/// This function will retry network requests.
+async fn resolve_accessions(
+    client: &NetworkInstance,
+    config: &Config,
+    arguments: &[String],
+    accessions: &mut Vec<String>,
+) -> Vec<(String, NamedRun)> {
+    let mut found = Vec::new();
+    let mut unresolved = Vec::new();
+    for accession in accessions.drain(..) {
+        match crate::mirror::read_names_and_srrs(client.clone(), &accession, config, arguments)
+            .await
+        {
+            Ok(runs) => {
+                for run in runs {
+                    if !found
+                        .iter()
+                        .any(|(_, known): &(String, NamedRun)| known.srr == run.srr)
+                    {
+                        found.push((accession.clone(), run));
                     }
-                });
-                continue;
+                }
+            }
+            Err(e) => {
+                progress!("Cannot resolve {}: {}", accession, e);
+                if !settled::<()>(Err(TaskError::resolving(&accession, &e))) {
+                    unresolved.push(accession);
+                }
+            }
+        }
+    }
+    *accessions = unresolved;
+    let (found_under, runs): (Vec<_>, Vec<_>) = found.into_iter().unzip();
+    found_under
+        .into_iter()
+        .zip(crate::ncbi::disambiguate(runs))
+        .collect()
+}
+
+/// Whether paired runs served interleaved are split into `_1` and `_2` files,
+/// unless the line asks for `keep_interleaved`.
+fn split_pairs(arguments: &[String]) -> bool {
+    !arguments.contains(&"keep_interleaved".into())
+}
+
+/// Download runs into `file_path`.
+async fn download_runs(
+    client: NetworkInstance,
+    config: Arc<Config>,
+    file_path: String,
+    mut runs: Vec<NamedRun>,
+    mirrors: Arc<Vec<Mirror>>,
+    split_pairs: bool,
+) -> Result<(), TaskError> {
+    join_task!(run in runs => {
+        let client = client.clone();
+        let config = config.clone();
+        let file_path = file_path.clone();
+        let mirrors = mirrors.clone();
+        async move {
+            download_run(client, config, file_path, run.clone(), mirrors, split_pairs)
+                .await
+                .map(|_|(run,()))
+        }
+    });
+    if runs.is_empty() {
+        Ok(())
+    } else {
+        Err(TaskError::Failed)
+    }
+}
+
+/// Download every run of the GSA experiments given as `(CRA, CRX)` into `file_path`.
+async fn download_gsa_experiments(
+    client: NetworkInstance,
+    config: Arc<Config>,
+    file_path: String,
+    mut experiments: Vec<(String, String)>,
+) -> Result<(), TaskError> {
+    join_task!(experiment in experiments=>{
+        let client = client.clone();
+        let file_path = file_path.clone();
+        let config = config.clone();
+        async move {
+            let runs = match read_runs_with_crx(
+                client.clone(), &experiment.0, &experiment.1, config.as_ref()
+            ).await {
+                Ok(v) => v,
+                Err(e) => {
+                    progress!("Cannot resolve {}/{}: {}", experiment.0, experiment.1, e);
+                    return Err(TaskError::resolving(&experiment.1, &e));
+                }
             };
-            let mut srx_list = Vec::from_iter(from..(to + 1));
-            join_task!(srx in srx_list=>{
+            let mut files = crate::cnbi::file_names(&runs);
+            join_task!(file in files => {
                 let client = client.clone();
-                let file_path = Arc::new(file_path.clone());
-                let arguments = arguments.clone();
-                let config = global_config.clone();
+                let config = config.clone();
+                let file_path = format!("{}/{}", file_path, file.0);
+                let crx = experiment.1.clone();
                 async move{
-                    let (id, srr) = match read_name_and_srr(
+                    download(
+                        client.clone(),
+                        file.1.url.clone(),
+                        config.as_ref(),
+                        file_path,
+                        file.1.md5.as_deref(),
+                        config.validate_fastq,
+                    )
+                    .await
+                    .map(|_|(file,()))
+                    // CNCB has no mirror to fall back on, denied access is for good
+                    .map_err(|e| match e {
+                        TaskError::ControlledAccess(_) => TaskError::ControlledAccess(crx),
+                        e => e,
+                    })
+                }
+            });
+            // Summary of the files delivered
+            for (name, file) in crate::cnbi::file_names(&runs)
+                .iter()
+                .filter(|file| !files.contains(file))
+            {
+                match &file.md5 {
+                    Some(md5) => progress!("{} verified with MD5 {}", name, md5),
+                    None => progress!("{} has no published MD5, not verified", name),
+                }
+            }
+            if files.is_empty() {
+                Ok((experiment,()))
+            } else {
+                Err(TaskError::Failed)
+            }
+        }
+    });
+    if experiments.is_empty() {
+        Ok(())
+    } else {
+        Err(TaskError::Failed)
+    }
+}
+
+/// Download the files of a run into `file_path`.
+/// Mirrors are tried in order until one of them delivers every file of the run.
+/// A run the metadata reports under controlled access is given up on without trying
+/// the other mirrors, while a mirror denying access only fails on that mirror.
+/// With `verify_read_counts` set, the reads and bases of the files have to add up
+/// to what the archive reports, otherwise the run fails and the files are kept for a look.
+/// With `split_pairs` set, a paired run served interleaved is saved as `_1` and `_2` files.
+async fn download_run(
+    client: NetworkInstance,
+    config: Arc<Config>,
+    file_path: String,
+    run: NamedRun,
+    mirrors: Arc<Vec<Mirror>>,
+    split_pairs: bool,
+) -> Result<(), TaskError> {
+    let NamedRun {
+        name,
+        srr,
+        counts,
+        paired,
+    } = run;
+    // Bare runs come without metadata
+    let (counts, paired) = match (counts, paired) {
+        (None, None) if config.verify_read_counts || split_pairs => {
+            match read_run_info(&client, &srr, config.as_ref()).await {
+                Some(run) if run.is_controlled() => {
+                    progress!(
+                        "{} is under controlled access on dbGaP, consent {}, skipped",
+                        srr,
+                        run.consent
+                    );
+                    return Err(TaskError::ControlledAccess(srr));
+                }
+                Some(run) => (run.read_counts(), run.is_paired()),
+                None => (None, None),
+            }
+        }
+        known => known,
+    };
+    let counts = counts.filter(|_| config.verify_read_counts);
+    if config.verify_read_counts && counts.is_none() {
+        progress!("No spot count known for {}, not checked", srr);
+    }
+    let validate = config.validate_fastq || counts.is_some();
+    let mut last_error = TaskError::Failed;
+    for mirror in mirrors.iter() {
+        let files = match mirror.run_files(&client, &srr, config.as_ref()).await {
+            Ok(v) => v,
+            Err(e) => {
+                progress!("Cannot find the files of {} on {}: {}", srr, mirror, e);
+                if e.is_controlled_access() {
+                    return Err(TaskError::ControlledAccess(srr));
+                }
+                continue;
+            }
+        };
+        let mut delivered = true;
+        let mut written = Vec::new();
+        // Every file this mirror was asked for, complete or not
+        let mut attempted = Vec::new();
+        // `None` once a file could not be counted
+        let mut stats = Some(FastqStats::default());
+        let split = split_pairs && paired == Some(true) && mirror.serves_interleaved();
+        for RunFile { suffix, url, md5 } in files {
+            let result = if split {
+                let file_paths =
+                    [1, 2].map(|mate| format!("{}/{}_{}{}", file_path, name, mate, suffix));
+                attempted.extend(file_paths.clone());
+                download_paired(
                     client.clone(),
-                    srx,
+                    url,
                     config.as_ref(),
-                    arguments.as_ref(),
+                    file_paths.clone(),
+                    validate,
                 )
                 .await
-                {
-                    Ok(v) => v,
-                    Err(_) => {
-                        println!("max retry times reached for SRX {}", srx);
-                        return Err(());
-                    }
-                };
-                let file_path = format!("{}/{}.fastq.gz", file_path, id);
-               download(
+                .map(|file_stats| (file_paths.to_vec(), file_stats))
+            } else {
+                let file_path = format!("{}/{}{}", file_path, name, suffix);
+                attempted.push(file_path.clone());
+                download(
                     client.clone(),
-                    Url::from_str(&format!(
-                        "https://www.be-md.ncbi.nlm.nih.gov/Traces/sra-reads-be/fastq?acc={}",
-                        srr
-                    ))
-                    .unwrap(),
+                    url,
                     config.as_ref(),
-                    file_path,
-                ).await.map(|_|(srx,()))
+                    file_path.clone(),
+                    md5.as_deref(),
+                    validate,
+                )
+                .await
+                .map(|file_stats| (vec![file_path], file_stats))
+            };
+            match result {
+                Ok((file_paths, file_stats)) => {
+                    written.extend(file_paths);
+                    stats = stats.zip(file_stats).map(|(stats, file)| stats + file);
+                }
+                // Access denied by one mirror is not taken for controlled access,
+                // the next mirror may serve the run.
+                Err(TaskError::ControlledAccess(_)) => {
+                    last_error = TaskError::Failed;
+                    delivered = false;
+                    break;
                 }
-            });
-            continue;
-        }
-        if item[..3] == "CRA".to_string() {
-            let mut item_line = item.split_whitespace();
-            let cra_number: usize = item_line.next().unwrap()[3..].parse().unwrap();
-            let (from, to) = match read_number(item_line.next().unwrap()) {
-                Ok(v) => v,
                 Err(e) => {
-                    println!("{}", e);
-                    continue;
+                    last_error = e;
+                    delivered = false;
+                    break;
                 }
-            };
-            let arguments = Arc::new(
-                item_line
-                    .map(|str| str.to_string())
-                    .collect::<Vec<String>>(),
-            );
-            if arguments.contains(&"preflight".into()) {
-                let mut crx_list = Vec::from_iter(from..(to + 1));
-                join_task!(crx in crx_list=>{
-                        let client = client.clone();
-                        let config = global_config.clone();
-                        crate::cnbi::preflight(client,cra_number,crx,config)
-                });
-                continue;
             }
-            let mut crx_list = Vec::from_iter(from..(to + 1));
-            join_task!(crx in crx_list=>{
-                let client = client.clone();
-                let file_path = file_path.clone();
-                let config = global_config.clone();
-                async move {
-                    let (alias, mut filenames, crr) =
-                        read_alias_and_crr_with_crx(
-                            client.clone(), cra_number, crx, config.clone()
-                        ).await.map_err(|_|())?;
-                    join_task!(filename in filenames => {
-                        let client = client.clone();
-                        let config = config.clone();
-                        let file_path = format!("{}/{}_{}", file_path, alias, filename);
-                        async move{
-                            download(
-                                client.clone(),
-                                Url::from_str(&format!(
-                                    "https://download.cncb.ac.cn/gsa/CRA{:0>6}/CRR{}/{}",
-                                    cra_number, crr, filename
-                                )).unwrap(),
-                                config.as_ref(),
-                                file_path
-                            ).await.map(|_|(filename.clone(),()))
-                        }
-                    });
-                    Ok::<(usize,()),()>((crx,()))
-                }
-            });
         }
-        // When the line is only for configuration
-        let mut config_line = item.split_whitespace();
-        let maybe_file_path = config_line.next().unwrap().to_owned().replace("\\", "/");
-        match fs::create_dir_all(maybe_file_path.clone()).await {
-            Ok(_) => file_path = maybe_file_path,
-            Err(e) => {
-                println!(
-                    "Cannot use {} as working directory: {:?}",
-                    maybe_file_path, e
+        if !delivered {
+            progress!("{} failed on {}", srr, mirror);
+            // The next mirror may save to the same paths, and must not resume
+            // from the part files of a different server.
+            discard_files(&attempted).await;
+            continue;
+        }
+        match (counts, stats) {
+            (Some(counts), Some(stats)) if !counts.matches(stats.reads, stats.bases) => {
+                progress!(
+                    "{} from {} has {} reads and {} bases, but {} spots and {} bases are expected",
+                    srr,
+                    mirror,
+                    stats.reads,
+                    stats.bases,
+                    counts.spots,
+                    counts.bases
                 );
-                println!("Exiting...");
-                return;
+                // Layouts like technical reads or orphan mates legitimately differ
+                // from the spot count, so the data is left alone.
+                progress!("{} kept as downloaded: {}", srr, written.join(", "));
+                return Err(TaskError::Failed);
             }
-        };
+            (Some(_), Some(stats)) => progress!(
+                "{} matches the archive with {} reads and {} bases",
+                srr,
+                stats.reads,
+                stats.bases
+            ),
+            (Some(_), None) => progress!("Reads of {} cannot be counted, not checked", srr),
+            (None, _) => {}
+        }
+        progress!("{} delivered by {}", srr, mirror);
+        return Ok(());
     }
-    println!("All lines have been read");
+    Err(last_error)
 }
 
-/// To initate a download, the file path to write to and the SRR number is needed.
-async fn download(
-    client: NetworkInstance,
-    url: Url,
+/// Look up the metadata of a run that was not resolved through it.
+/// Runs that cannot be looked up are downloaded without it.
+async fn read_run_info(
+    client: &NetworkInstance,
+    srr: &str,
     config: &Config,
-    file_path: String,
-) -> Result<(), ()> {
-    let bytes = crate::with_retry!(
-        config.retry_times =>
-        client.get(url.clone(),config.download_timeout)
-    )?;
-    if let Err(e) = fs::write(&file_path, bytes).await {
-        println!("Failed to write to {}: {:?}", file_path, e);
-        Err(())
-    } else {
-        println!("{} finished and written to {}", url.to_string(), file_path);
-        Ok(())
-    }
-}
-
-/// Read SRX from syntax `SRX123456-789000`.
-/// The number can be supplied with decending order.
-fn read_number(srx: &str) -> Result<(usize, usize), ParseNumberError> {
-    if srx.contains("-") {
-        // When SRX is supplied in a range, inclusive
-        let mut split = srx[3..].split("-");
-        let maybe_num1 = split.next();
-        if let None = maybe_num1 {
-            return Err(ParseNumberError::IncompleteRange);
-        };
-        let num1 = maybe_num1
-            .unwrap()
-            .parse()
-            .map_err(|_| ParseNumberError::IllegalCharacter)?;
-        let maybe_num2 = split.next();
-        if let None = maybe_num2 {
-            return Err(ParseNumberError::IncompleteRange);
-        };
-        let num2 = maybe_num2
-            .unwrap()
-            .parse()
-            .map_err(|_| ParseNumberError::IllegalCharacter)?;
-        // The order of the SRX number is automatically determined
-        if num1 > num2 {
-            Ok((num2, num1))
-        } else {
-            Ok((num1, num2))
+) -> Option<crate::ncbi::RunInfo> {
+    match crate::ncbi::fetch_run_info(client, srr, config).await {
+        Ok(runs) => runs.into_iter().find(|run| run.run == srr),
+        Err(e) => {
+            progress!("Cannot read the metadata of {}: {}", srr, e);
+            None
         }
-    } else {
-        // Single srx
-        let num = srx[3..].parse().unwrap();
-        Ok((num, num))
     }
 }
 
+/// Why a task did not succeed, as told apart in the summary of `join_task!`.
 #[derive(Debug)]
-enum ParseNumberError {
-    IllegalCharacter,
-    IncompleteRange,
+pub enum TaskError {
+    /// Worth running the line again.
+    Failed,
+    /// The accession or file is under controlled access,
+    /// running the line again will not change anything.
+    ControlledAccess(String),
+    /// The file was downloaded but failed validation and has been removed.
+    /// Worth running the line again.
+    Invalid(String),
 }
-impl std::fmt::Display for ParseNumberError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::IllegalCharacter => f.write_str("Illegal character found"),
-            Self::IncompleteRange => f.write_str("Incomplete range"),
+impl TaskError {
+    /// Classify a failure to resolve `accession`.
+    fn resolving(accession: &str, e: &ncbi::ReadMetaError) -> Self {
+        if e.is_controlled_access() {
+            Self::ControlledAccess(accession.to_owned())
+        } else {
+            Self::Failed
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub retry_times: usize,
     pub read_meta_timeout: usize,
     pub max_concurrent_requests: usize,
-    pub download_timeout:usize,
+    pub download_timeout: usize,
+    /// Split a single file into up to this many parallel range requests.
+    /// `1` downloads every file over a single connection.
+    pub segments: usize,
+    /// Files are only split into segments of at least this many bytes.
+    pub min_segment_size: u64,
+    /// Delay before the first retry, doubled after every further failure.
+    pub retry_base_delay_ms: u64,
+    /// Upper bound of the delay between two retries.
+    pub retry_max_delay_ms: u64,
+    /// Requests per second allowed to each host.
+    pub rate_limits: HashMap<String, f64>,
+    /// Requests per second allowed to hosts not listed in `rate_limits`.
+    pub default_rate_limit: f64,
+    /// Sent along with every NCBI metadata request, falls back to `NCBI_API_KEY`.
+    /// Raises the rate limit of the NCBI metadata hosts to the keyed limit.
+    pub ncbi_api_key: Option<String>,
+    /// Mirrors SRA runs are downloaded from, in the order they are tried.
+    /// A run that fails on one mirror is retried on the next.
+    pub mirrors: Vec<Mirror>,
+    /// Decompress every downloaded `.fastq.gz` and check its records.
+    pub validate_fastq: bool,
+    /// Count the reads of every SRA run and compare them with its metadata.
+    /// Implies validation of the files of SRA runs, decompressing every one of them.
+    pub verify_read_counts: bool,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -306,9 +1093,27 @@ impl Default for Config {
             read_meta_timeout: 60,
             max_concurrent_requests: 3,
             download_timeout: 600,
+            segments: 1,
+            min_segment_size: 64 * 1024 * 1024,
+            retry_base_delay_ms: 500,
+            retry_max_delay_ms: 30_000,
+            rate_limits: HashMap::from([
+                // NCBI allows 3 requests per second without an API key
+                ("www.ncbi.nlm.nih.gov".to_owned(), 3.0),
+                ("eutils.ncbi.nlm.nih.gov".to_owned(), 3.0),
+                ("ngdc.cncb.ac.cn".to_owned(), 5.0),
+                ("www.be-md.ncbi.nlm.nih.gov".to_owned(), 3.0),
+                ("download.cncb.ac.cn".to_owned(), 10.0),
+            ]),
+            default_rate_limit: 5.0,
+            ncbi_api_key: None,
+            mirrors: vec![Mirror::Ncbi, Mirror::Ena, Mirror::Ddbj],
+            validate_fastq: false,
+            verify_read_counts: false,
         }
     }
 }
+
 pub enum Parameters {
     SRRNameOverride,
     LibraryNameOverride,
@@ -324,18 +1129,59 @@ impl TryFrom<&str> for Parameters {
     }
 }
 
+/// Hosts serving NCBI metadata, where the API key is attached.
+const NCBI_METADATA_HOSTS: [&str; 2] = ["www.ncbi.nlm.nih.gov", "eutils.ncbi.nlm.nih.gov"];
+/// Requests per second NCBI allows with an API key.
+const NCBI_KEYED_RATE_LIMIT: f64 = 10.0;
+
 #[derive(Debug, Clone)]
 pub struct NetworkInstance {
     semaphore: Arc<Semaphore>,
+    limiter: Arc<RateLimiter>,
     client: Client,
+    ncbi_api_key: Option<Arc<str>>,
 }
 impl NetworkInstance {
-    pub fn new(max_concurrent_requests: usize) -> Self {
+    pub fn new(config: &Config) -> Self {
+        let mut rate_limits = config.rate_limits.clone();
+        if config.ncbi_api_key.is_some() {
+            for host in NCBI_METADATA_HOSTS {
+                let rate = rate_limits
+                    .entry(host.to_owned())
+                    .or_insert(config.default_rate_limit);
+                *rate = rate.max(NCBI_KEYED_RATE_LIMIT);
+            }
+        }
         Self {
-            semaphore: Arc::new(Semaphore::new(max_concurrent_requests)),
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            limiter: Arc::new(RateLimiter::new(rate_limits, config.default_rate_limit)),
             client: reqwest::Client::new(),
+            ncbi_api_key: config.ncbi_api_key.as_deref().map(Arc::from),
         }
     }
+    /// Send a request once the rate limit of its host allows it,
+    /// and let the limiter know how the server responded.
+    async fn execute(
+        &self,
+        mut request: Request,
+        timeout: Duration,
+    ) -> Result<Response, GetReqError> {
+        let host = request.url().host_str().unwrap_or_default().to_owned();
+        if let Some(api_key) = &self.ncbi_api_key {
+            if NCBI_METADATA_HOSTS.contains(&host.as_str()) {
+                request
+                    .url_mut()
+                    .query_pairs_mut()
+                    .append_pair("api_key", api_key);
+            }
+        }
+        self.limiter.acquire(&host).await;
+        let response = time::timeout(timeout, self.client.execute(request))
+            .await
+            .map_err(|_| GetReqError::Timeout)??;
+        self.limiter.observe(&host, &response);
+        Ok(response)
+    }
     /// Perform a get request to the given url.
     /// Panic when the url is malformed.
     pub async fn get_by_str(&self, url: String, timeout: usize) -> Result<Bytes, GetReqError> {
@@ -344,50 +1190,658 @@ impl NetworkInstance {
     }
     /// Perform a get request to the given url.
     pub async fn get(&self, url: Url, timeout: usize) -> Result<Bytes, GetReqError> {
-        let permit = self
+        let _permit = self
             .semaphore
             .acquire()
             .await
             .expect("Semaphore to be open");
-        let request = Request::new(Method::GET, url.clone());
-        use futures_timer::Delay;
-        use std::time::Duration;
-        use tokio::select;
-        let timeout =
-            Duration::from_secs(timeout.try_into().expect("on machine with 64-bit or less"));
-        let mut timer = Delay::new(timeout);
-        let response = loop {
-            select! {
-                maybe_response = self.client.execute(request) =>{
-                    break maybe_response
-                }
-                _ = &mut timer =>{
-                    return Err(GetReqError::Timeout);
-                }
-            }
-        }?;
-        timer.reset(timeout);
-        let bytes = loop {
-            select! {
-                maybe_bytes = response.bytes() =>{
-                    break maybe_bytes
-                }
-                _ = &mut timer =>{
-                    return Err(GetReqError::Timeout);
-                }
-            }
-        }?;
-        drop(permit);
+        let request = Request::new(Method::GET, url);
+        let timeout = timeout_from_secs(timeout);
+        let response = self.execute(request, timeout).await?;
+        if let Some(e) = GetReqError::from_status(response.status()) {
+            return Err(e);
+        }
+        let bytes = time::timeout(timeout, response.bytes())
+            .await
+            .map_err(|_| GetReqError::Timeout)??;
         Ok(bytes)
     }
+    /// Perform a get request to the given url and stream the body into `file_path`.
+    /// `timeout` bounds the wait for the response head and for every following chunk,
+    /// so a slow but steady transfer of a large file is never cut short.
+    ///
+    /// The body is written to `<file_path>.part` first. If that file already exists
+    /// from an earlier attempt, the transfer is resumed with a `Range` request; when
+    /// the server does not honor it, the file is downloaded again from the start.
+    /// The part file is renamed to `file_path` only after the body is complete.
+    pub async fn get_to_file(
+        &self,
+        url: Url,
+        timeout: usize,
+        file_path: &str,
+    ) -> Result<Downloaded, GetReqError> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Semaphore to be open");
+        let part_path = format!("{}.part", file_path);
+        let mut md5 = Md5::new();
+        let size = self
+            .get_range_to_file(
+                &url,
+                timeout_from_secs(timeout),
+                &part_path,
+                None,
+                Some(&mut md5),
+            )
+            .await?;
+        fs::rename(&part_path, file_path).await?;
+        Ok(Downloaded {
+            size,
+            md5: format!("{:x}", md5.finalize()),
+        })
+    }
+    /// Perform a get request for an interleaved `.fastq.gz` and split it into
+    /// one gzip compressed file per mate while the body streams in.
+    /// Both files are written as `<file_path>.part` and renamed once complete.
+    /// The split output cannot be resumed, an interrupted transfer removes both
+    /// part files and starts over.
+    pub async fn get_to_paired_files(
+        &self,
+        url: Url,
+        timeout: usize,
+        file_paths: [&str; 2],
+    ) -> Result<(), GetReqError> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Semaphore to be open");
+        let timeout = timeout_from_secs(timeout);
+        let mut response = self
+            .execute(Request::new(Method::GET, url), timeout)
+            .await?;
+        if let Some(e) = GetReqError::from_status(response.status()) {
+            return Err(e);
+        }
+        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or_default();
+            if content_type.starts_with("text/html") {
+                return Err(GetReqError::UnexpectedContentType(content_type.to_owned()));
+            }
+        }
+        let part_paths = file_paths.map(|file_path| format!("{}.part", file_path));
+        // Decompressing and compressing again blocks, so it happens on its own thread.
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(PAIRED_CHANNEL_SIZE);
+        let writer = {
+            let part_paths = part_paths.clone();
+            task::spawn_blocking(move || -> std::io::Result<()> {
+                let mates = [
+                    std::fs::File::create(&part_paths[0])?,
+                    std::fs::File::create(&part_paths[1])?,
+                ]
+                .map(|file| std::io::BufWriter::with_capacity(WRITE_BUFFER_SIZE, file));
+                let mut decoder = MultiGzDecoder::new(Deinterleaver::new(mates));
+                while let Some(chunk) = receiver.blocking_recv() {
+                    std::io::Write::write_all(&mut decoder, &chunk)?;
+                }
+                for mut mate in decoder.finish()?.finish()? {
+                    std::io::Write::flush(&mut mate)?;
+                }
+                Ok(())
+            })
+        };
+        let streamed = async {
+            while let Some(chunk) = time::timeout(timeout, response.chunk())
+                .await
+                .map_err(|_| GetReqError::Timeout)??
+            {
+                if sender.send(chunk).await.is_err() {
+                    // The writer gave up, its error is reported below.
+                    break;
+                }
+            }
+            Ok::<_, GetReqError>(())
+        }
+        .await;
+        drop(sender);
+        let written = writer.await.expect("writer not to panic");
+        if streamed.is_err() || written.is_err() {
+            // Nothing can be resumed from a split body.
+            for part_path in part_paths.iter() {
+                let _ = fs::remove_file(part_path).await;
+            }
+        }
+        streamed?;
+        written?;
+        for (part_path, file_path) in part_paths.iter().zip(file_paths) {
+            fs::rename(part_path, file_path).await?;
+        }
+        Ok(())
+    }
+    /// Like [`Self::get_to_file`], but splits the body into up to `segments` byte ranges
+    /// of at least `min_segment_size` bytes and fetches them in parallel.
+    /// Every segment holds its own permit, so the segments of one file share
+    /// `max_concurrent_requests` with everything else.
+    ///
+    /// Segments are kept in `<file_path>.part<N>-of-<count>` until all of them are complete,
+    /// then joined into `file_path`. Each segment resumes on its own when retried.
+    /// Segments left by a download split into a different count are thrown away,
+    /// as their bytes start at different offsets.
+    /// Servers that do not report a length or do not accept ranges get a plain
+    /// [`Self::get_to_file`].
+    pub async fn get_to_file_segmented(
+        &self,
+        url: Url,
+        timeout: usize,
+        file_path: &str,
+        segments: usize,
+        min_segment_size: u64,
+    ) -> Result<Downloaded, GetReqError> {
+        let total = match self.probe_length(&url, timeout_from_secs(timeout)).await? {
+            Some(total) => total,
+            None => return self.get_to_file(url, timeout, file_path).await,
+        };
+        let count = segments.min((total / min_segment_size.max(1)) as usize);
+        if count < 2 {
+            remove_stale_segments(file_path, None).await;
+            return self.get_to_file(url, timeout, file_path).await;
+        }
+        remove_stale_segments(file_path, Some(count)).await;
+        let segment_size = total.div_ceil(count as u64);
+        let mut join_set = JoinSet::new();
+        for index in 0..count {
+            let this = self.clone();
+            let url = url.clone();
+            let part_path = segment_path(file_path, index, count);
+            let start = index as u64 * segment_size;
+            let end = (start + segment_size).min(total) - 1;
+            join_set.spawn(async move {
+                let _permit = this
+                    .semaphore
+                    .acquire()
+                    .await
+                    .expect("Semaphore to be open");
+                let size = this
+                    .get_range_to_file(
+                        &url,
+                        timeout_from_secs(timeout),
+                        &part_path,
+                        Some((start, end)),
+                        None,
+                    )
+                    .await?;
+                let expected = end - start + 1;
+                if size != expected {
+                    // A retry fetches the segment again from the start.
+                    fs::remove_file(&part_path).await?;
+                    return Err(GetReqError::LengthMismatch {
+                        expected,
+                        actual: size,
+                    });
+                }
+                Ok(())
+            });
+        }
+        while let Some(result) = join_set.join_next().await {
+            result.expect("segment task not to panic")?;
+        }
+        // Every segment is complete, join them in order and hash the result on the way.
+        let part_path = format!("{}.part", file_path);
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(&part_path).await?);
+        let mut md5 = Md5::new();
+        let mut buffer = vec![0; WRITE_BUFFER_SIZE];
+        let mut size = 0;
+        for index in 0..count {
+            let mut segment = File::open(segment_path(file_path, index, count)).await?;
+            loop {
+                let read = segment.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                md5.update(&buffer[..read]);
+                file.write_all(&buffer[..read]).await?;
+                size += read as u64;
+            }
+        }
+        file.flush().await?;
+        drop(file);
+        if size != total {
+            fs::remove_file(&part_path).await?;
+            return Err(GetReqError::LengthMismatch {
+                expected: total,
+                actual: size,
+            });
+        }
+        fs::rename(&part_path, file_path).await?;
+        for index in 0..count {
+            fs::remove_file(segment_path(file_path, index, count)).await?;
+        }
+        Ok(Downloaded {
+            size,
+            md5: format!("{:x}", md5.finalize()),
+        })
+    }
+    /// Ask the server for the length of the body with a `HEAD` request.
+    /// Returns `None` when the length is unknown or byte ranges are not accepted.
+    async fn probe_length(&self, url: &Url, timeout: Duration) -> Result<Option<u64>, GetReqError> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Semaphore to be open");
+        let request = Request::new(Method::HEAD, url.clone());
+        let response = self.execute(request, timeout).await?;
+        let accepts_ranges = response
+            .headers()
+            .get(ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes() == b"bytes");
+        if !response.status().is_success() || !accepts_ranges {
+            return Ok(None);
+        }
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()))
+    }
+    /// Stream the body, or the inclusive byte `range` of it, into `part_path`.
+    /// Whatever `part_path` already holds is kept and only the rest is requested.
+    /// Everything ending up in `part_path` is fed to `md5`, the kept part included.
+    /// The caller is expected to hold a permit.
+    async fn get_range_to_file(
+        &self,
+        url: &Url,
+        timeout: Duration,
+        part_path: &str,
+        range: Option<(u64, u64)>,
+        mut md5: Option<&mut Md5>,
+    ) -> Result<u64, GetReqError> {
+        let (offset, expected) = match range {
+            Some((start, end)) => (start, Some(end - start + 1)),
+            None => (0, None),
+        };
+        let mut resume_from = match fs::metadata(part_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        if expected.is_some_and(|expected| resume_from >= expected) {
+            // Segment finished by an earlier attempt.
+            return Ok(resume_from);
+        }
+        let mut response = loop {
+            let mut request = Request::new(Method::GET, url.clone());
+            if resume_from > 0 || range.is_some() {
+                let end = range.map(|(_, end)| end.to_string()).unwrap_or_default();
+                request.headers_mut().insert(
+                    RANGE,
+                    HeaderValue::from_str(&format!("bytes={}-{}", offset + resume_from, end))
+                        .expect("range to be valid header value"),
+                );
+            }
+            let response = self.execute(request, timeout).await?;
+            let ranged = resume_from > 0 || range.is_some();
+            // 416 is expected when the part file is already complete.
+            if !ranged || response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+                if let Some(e) = GetReqError::from_status(response.status()) {
+                    return Err(e);
+                }
+            }
+            if !ranged {
+                break response;
+            }
+            match (response.status(), content_range(&response)) {
+                (StatusCode::PARTIAL_CONTENT, Some((Some(start), _)))
+                    if start == offset + resume_from =>
+                {
+                    if resume_from > 0 {
+                        progress!("Resuming {} from byte {}", part_path, resume_from);
+                    }
+                    break response;
+                }
+                (StatusCode::RANGE_NOT_SATISFIABLE, Some((None, Some(total))))
+                    if range.is_none() && total == resume_from =>
+                {
+                    // The part file already holds the whole body.
+                    if let Some(md5) = md5 {
+                        hash_file(part_path, md5).await?;
+                    }
+                    return Ok(resume_from);
+                }
+                (StatusCode::OK, _) if range.is_none() => {
+                    // Range not supported, the full body is on its way.
+                    resume_from = 0;
+                    break response;
+                }
+                (StatusCode::PARTIAL_CONTENT, _) | (StatusCode::RANGE_NOT_SATISFIABLE, _)
+                    if resume_from > 0 =>
+                {
+                    // The part file does not line up with what the server has, start over.
+                    resume_from = 0;
+                }
+                (status, _) => return Err(GetReqError::UnexpectedStatus(status)),
+            }
+        };
+        // Error pages are sometimes served with a success status.
+        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or_default();
+            if content_type.starts_with("text/html") {
+                return Err(GetReqError::UnexpectedContentType(content_type.to_owned()));
+            }
+        }
+        let file = if resume_from > 0 {
+            if let Some(md5) = md5.as_deref_mut() {
+                hash_file(part_path, md5).await?;
+            }
+            OpenOptions::new().append(true).open(part_path).await?
+        } else {
+            File::create(part_path).await?
+        };
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+        let mut written = resume_from;
+        while let Some(chunk) = time::timeout(timeout, response.chunk())
+            .await
+            .map_err(|_| GetReqError::Timeout)??
+        {
+            if let Some(md5) = md5.as_deref_mut() {
+                md5.update(&chunk);
+            }
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+}
+
+/// Path of segment `index` of `file_path` split into `count` segments.
+/// The count is part of the name, so a segment is only resumed under the same layout.
+fn segment_path(file_path: &str, index: usize, count: usize) -> String {
+    format!("{}.part{}-of-{}", file_path, index, count)
+}
+
+/// Whether `file_name` is a segment of `base_name`, and of how many segments.
+/// Segments named before the count was recorded are of an unknown count.
+fn segment_count(base_name: &str, file_name: &str) -> Option<Option<usize>> {
+    let segment = file_name.strip_prefix(base_name)?.strip_prefix(".part")?;
+    let is_number = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+    match segment.split_once("-of-") {
+        Some((index, count)) if is_number(index) && is_number(count) => Some(count.parse().ok()),
+        None if is_number(segment) => Some(None),
+        _ => None,
+    }
 }
 
+/// Remove the segments of `file_path` not split into `count` segments,
+/// or every segment of it when `count` is `None`.
+async fn remove_stale_segments(file_path: &str, count: Option<usize>) {
+    let path = std::path::Path::new(file_path);
+    let Some(base_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return;
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let Ok(mut entries) = fs::read_dir(directory).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name();
+        let Some(segments) = file_name
+            .to_str()
+            .and_then(|file_name| segment_count(base_name, file_name))
+        else {
+            continue;
+        };
+        if count.is_none() || segments != count {
+            progress!("Discarding stale segment {}", entry.path().display());
+            if let Err(e) = fs::remove_file(entry.path()).await {
+                progress!("Cannot remove {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+/// A file written to disk.
+#[derive(Debug)]
+pub struct Downloaded {
+    pub size: u64,
+    /// Lowercase hex MD5 of the whole file.
+    pub md5: String,
+}
+
+/// Feed the content of a file to `md5`.
+async fn hash_file(path: &str, md5: &mut Md5) -> Result<(), std::io::Error> {
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0; WRITE_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        md5.update(&buffer[..read]);
+    }
+}
+
+/// Read the `Content-Range` header of a response as `(start, total)`.
+/// `start` is `None` for the unsatisfied form `bytes */<total>`,
+/// `total` is `None` when the server reports it as `*`.
+fn content_range(response: &Response) -> Option<(Option<u64>, Option<u64>)> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = match range {
+        "*" => None,
+        range => Some(range.split_once('-')?.0.parse().ok()?),
+    };
+    Some((start, total.parse().ok()))
+}
+
+/// Size of the buffer sitting between the network stream and the output file.
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+/// Chunks of an interleaved body waiting to be split.
+const PAIRED_CHANNEL_SIZE: usize = 16;
+
+fn timeout_from_secs(secs: usize) -> Duration {
+    Duration::from_secs(secs.try_into().expect("on machine with 64-bit or less"))
+}
+
+#[derive(Debug)]
 pub enum GetReqError {
     Timeout,
+    /// The server answered 404.
+    NotFound,
+    /// The server answered 401 or 403.
+    Forbidden(StatusCode),
+    /// The server answered 429.
+    TooManyRequests,
+    /// Any other 4xx answer.
+    ClientError(StatusCode),
+    /// Any 5xx answer.
+    ServerError(StatusCode),
+    /// A status that is not an error but not what the request asked for,
+    /// e.g. a full body when only a byte range was requested.
+    UnexpectedStatus(StatusCode),
+    /// A web page was served where a file was expected.
+    UnexpectedContentType(String),
+    /// The downloaded file does not match its published MD5.
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// A body or byte range came back with a different length than asked for.
+    LengthMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    Io(std::io::Error),
     Other(reqwest::Error),
 }
+impl GetReqError {
+    /// Classify a response by its status code.
+    /// Returns `None` for success statuses.
+    fn from_status(status: StatusCode) -> Option<Self> {
+        match status {
+            _ if status.is_success() => None,
+            StatusCode::NOT_FOUND => Some(Self::NotFound),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(Self::Forbidden(status)),
+            StatusCode::TOO_MANY_REQUESTS => Some(Self::TooManyRequests),
+            _ if status.is_client_error() => Some(Self::ClientError(status)),
+            _ if status.is_server_error() => Some(Self::ServerError(status)),
+            _ => Some(Self::UnexpectedStatus(status)),
+        }
+    }
+}
+impl std::fmt::Display for GetReqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => f.write_str("Request timed out"),
+            Self::NotFound => f.write_str("Not found"),
+            Self::Forbidden(status) => write!(f, "Access denied: {}", status),
+            Self::TooManyRequests => f.write_str("Too many requests"),
+            Self::ClientError(status) => write!(f, "Client error: {}", status),
+            Self::ServerError(status) => write!(f, "Server error: {}", status),
+            Self::UnexpectedStatus(status) => write!(f, "Unexpected status: {}", status),
+            Self::UnexpectedContentType(content_type) => {
+                write!(f, "Unexpected content type: {}", content_type)
+            }
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "MD5 mismatch: expected {}, got {}", expected, actual)
+            }
+            Self::LengthMismatch { expected, actual } => {
+                write!(f, "Expected {} bytes, got {}", expected, actual)
+            }
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
 impl From<reqwest::Error> for GetReqError {
     fn from(value: reqwest::Error) -> Self {
-        Self::Other(value)
+        // The error prints its URL, which must not give away the NCBI API key.
+        let has_api_key = value
+            .url()
+            .is_some_and(|url| url.query_pairs().any(|(key, _)| key == "api_key"));
+        if has_api_key {
+            Self::Other(value.without_url())
+        } else {
+            Self::Other(value)
+        }
+    }
+}
+impl From<std::io::Error> for GetReqError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_with(header: reqwest::header::HeaderName, value: &str) -> Response {
+        http::Response::builder()
+            .header(header, value)
+            .body("")
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn segments_record_their_layout() {
+        let path = segment_path("out/SRR1.fastq.gz", 1, 4);
+        assert_eq!(path, "out/SRR1.fastq.gz.part1-of-4");
+        assert_eq!(
+            segment_count("SRR1.fastq.gz", "SRR1.fastq.gz.part1-of-4"),
+            Some(Some(4))
+        );
+        // Segments of an unknown layout
+        assert_eq!(
+            segment_count("SRR1.fastq.gz", "SRR1.fastq.gz.part1"),
+            Some(None)
+        );
+        assert_eq!(segment_count("SRR1.fastq.gz", "SRR1.fastq.gz.part"), None);
+        assert_eq!(
+            segment_count("SRR1.fastq.gz", "SRR1.fastq.gz.part1-of-"),
+            None
+        );
+        assert_eq!(
+            segment_count("SRR1.fastq.gz", "SRR10.fastq.gz.part1-of-4"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn segments_of_other_layouts_are_removed() {
+        let directory = std::env::temp_dir().join(format!("segments-{}", std::process::id()));
+        fs::create_dir_all(&directory).await.unwrap();
+        let file_path = directory.join("SRR1.fastq.gz").display().to_string();
+        for name in ["part0-of-2", "part1-of-2", "part1-of-3", "part1", "part"] {
+            fs::write(format!("{}.{}", file_path, name), b"")
+                .await
+                .unwrap();
+        }
+        remove_stale_segments(&file_path, Some(2)).await;
+        let mut left = Vec::new();
+        let mut entries = fs::read_dir(&directory).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            left.push(entry.file_name().into_string().unwrap());
+        }
+        left.sort();
+        fs::remove_dir_all(&directory).await.unwrap();
+        assert_eq!(
+            left,
+            [
+                "SRR1.fastq.gz.part",
+                "SRR1.fastq.gz.part0-of-2",
+                "SRR1.fastq.gz.part1-of-2"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_do_not_print_the_api_key() {
+        let request = |url: &'static str| async move {
+            GetReqError::from(Client::new().get(url).send().await.unwrap_err()).to_string()
+        };
+        // Nothing listens on port 1.
+        let error = request("http://127.0.0.1:1/efetch.fcgi?db=sra&api_key=secret").await;
+        assert!(!error.contains("secret"), "{}", error);
+        let error = request("http://127.0.0.1:1/efetch.fcgi?db=sra").await;
+        assert!(error.contains("127.0.0.1"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn controlled_access_is_not_a_failure() {
+        let mut accessions = ["SRX1", "SRX2", "SRX3"].map(String::from).to_vec();
+        join_task!(accession in accessions => {
+            async move {
+                match accession.as_str() {
+                    "SRX1" => Ok((accession, ())),
+                    "SRX2" => Err(TaskError::ControlledAccess("phs000001".to_owned())),
+                    _ => Err(TaskError::Failed),
+                }
+            }
+        });
+        assert_eq!(accessions, ["SRX3"]);
+        assert!(settled::<()>(Err(TaskError::ControlledAccess(
+            "HRA1".to_owned()
+        ))));
+        assert!(!settled::<()>(Err(TaskError::Failed)));
+        let controlled = CONTROLLED_ACCESS.lock().unwrap().clone();
+        assert!(controlled.contains(&"phs000001".to_owned()));
+        assert!(controlled.contains(&"HRA1".to_owned()));
+    }
+
+    #[test]
+    fn reads_content_range() {
+        let range = |value| content_range(&response_with(CONTENT_RANGE, value));
+        assert_eq!(range("bytes 100-199/1000"), Some((Some(100), Some(1000))));
+        assert_eq!(range("bytes */1000"), Some((None, Some(1000))));
+        assert_eq!(range("bytes 100-199/*"), Some((Some(100), None)));
+        assert_eq!(range("bytes 100/1000"), None);
+        assert_eq!(range("items 0-9/10"), None);
+        assert_eq!(content_range(&http::Response::new("").into()), None);
     }
 }
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ncbi::{NamedRun, ReadMetaError},
+    Config, NetworkInstance, TaskError,
+};
+
+/// Archives serving the runs of the SRA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mirror {
+    Ncbi,
+    Ena,
+    Ddbj,
+}
+impl Mirror {
+    const ALL: [Self; 3] = [Self::Ncbi, Self::Ena, Self::Ddbj];
+
+    /// Whether both mates of a paired run come interleaved in a single file.
+    pub fn serves_interleaved(self) -> bool {
+        matches!(self, Self::Ncbi)
+    }
+    /// The order in which mirrors are tried for a line.
+    /// A mirror named in the line arguments goes first, the rest follows the config.
+    pub fn order(arguments: &[String], config: &Config) -> Vec<Self> {
+        let mut mirrors = config.mirrors.clone();
+        if mirrors.is_empty() {
+            mirrors.push(Self::Ncbi);
+        }
+        if let Some(requested) = Self::ALL
+            .into_iter()
+            .find(|mirror| arguments.contains(&mirror.to_string()))
+        {
+            mirrors.retain(|mirror| *mirror != requested);
+            mirrors.insert(0, requested);
+        }
+        mirrors
+    }
+    /// The mirrors to resolve a line on, in the order of [`Mirror::order`].
+    /// Mirrors sharing their run metadata are asked only once.
+    fn resolution_order(arguments: &[String], config: &Config) -> Vec<Self> {
+        let mut sources = Vec::new();
+        let mut mirrors = Self::order(arguments, config);
+        mirrors.retain(|mirror| {
+            let source = mirror.metadata_source();
+            let first = !sources.contains(&source);
+            sources.push(source);
+            first
+        });
+        mirrors
+    }
+    /// The mirror whose run metadata this one serves.
+    fn metadata_source(self) -> Self {
+        match self {
+            // DDBJ shares its run metadata with NCBI.
+            Self::Ncbi | Self::Ddbj => Self::Ncbi,
+            Self::Ena => Self::Ena,
+        }
+    }
+    /// Resolve `accession` to its runs, with the name to save them under.
+    /// This function will retry network requests.
+    pub async fn read_names_and_srrs(
+        self,
+        client: NetworkInstance,
+        accession: &str,
+        config: &Config,
+        arguments: &[String],
+    ) -> Result<Vec<NamedRun>, ReadMetaError> {
+        match self {
+            // DDBJ shares its run metadata with NCBI.
+            Self::Ncbi | Self::Ddbj => {
+                crate::ncbi::read_names_and_srrs(client, accession, config, arguments).await
+            }
+            Self::Ena => {
+                crate::ena::read_names_and_srrs(client, accession, config, arguments).await
+            }
+        }
+    }
+    /// The files of a run on this mirror.
+    /// This function will retry network requests.
+    pub async fn run_files(
+        self,
+        client: &NetworkInstance,
+        srr: &str,
+        config: &Config,
+    ) -> Result<Vec<RunFile>, ReadMetaError> {
+        match self {
+            // The FASTQ is generated on request, there is no checksum to compare with.
+            Self::Ncbi => Ok(vec![RunFile {
+                suffix: ".fastq.gz".to_owned(),
+                url: Url::parse(&format!(
+                    "https://www.be-md.ncbi.nlm.nih.gov/Traces/sra-reads-be/fastq?acc={}",
+                    srr
+                ))
+                .map_err(|e| ReadMetaError::Malformed(format!("run {}: {}", srr, e)))?,
+                md5: None,
+            }]),
+            Self::Ena => crate::ena::run_files(client, srr, config).await,
+            Self::Ddbj => crate::ddbj::run_files(client, srr, config).await,
+        }
+    }
+}
+/// A file of a run on a mirror.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunFile {
+    /// Follows the name of the run in the file name, e.g. `_1.fastq.gz`.
+    pub suffix: String,
+    pub url: Url,
+    /// Published MD5 of the file, when the mirror has one.
+    pub md5: Option<String>,
+}
+
+impl std::fmt::Display for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ncbi => f.write_str("ncbi"),
+            Self::Ena => f.write_str("ena"),
+            Self::Ddbj => f.write_str("ddbj"),
+        }
+    }
+}
+
+/// Resolve `accession` on the first mirror that knows it.
+/// This function will retry network requests.
+pub async fn read_names_and_srrs(
+    client: NetworkInstance,
+    accession: &str,
+    config: &Config,
+    arguments: &[String],
+) -> Result<Vec<NamedRun>, ReadMetaError> {
+    let mut last_error = None;
+    for mirror in Mirror::resolution_order(arguments, config) {
+        match mirror
+            .read_names_and_srrs(client.clone(), accession, config, arguments)
+            .await
+        {
+            Ok(runs) => return Ok(runs),
+            // Mirrors do not hand out what one of them keeps under controlled access.
+            Err(e) if e.is_controlled_access() => return Err(e),
+            Err(e) => {
+                crate::progress!("Cannot resolve {} on {}: {}", accession, mirror, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("at least one mirror to be tried"))
+}
+
+pub async fn preflight(
+    client: NetworkInstance,
+    accession: String,
+    config: Arc<Config>,
+    arguments: Arc<Vec<String>>,
+) -> Result<Vec<NamedRun>, TaskError> {
+    match read_names_and_srrs(client, &accession, config.as_ref(), arguments.as_ref()).await {
+        Ok(runs) => {
+            for run in runs.iter() {
+                match run.counts {
+                    Some(counts) => crate::progress!(
+                        "Found {} with {} in {}: {} spots, {} bases",
+                        run.name,
+                        run.srr,
+                        accession,
+                        counts.spots,
+                        counts.bases
+                    ),
+                    None => {
+                        crate::progress!("Found {} with {} in {}", run.name, run.srr, accession)
+                    }
+                }
+            }
+            Ok(runs)
+        }
+        Err(e) => {
+            crate::progress!("Preflight for {} failed: {}", accession, e);
+            Err(TaskError::resolving(&accession, &e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_is_resolved_once_per_source() {
+        let config = Config::default();
+        assert_eq!(
+            Mirror::resolution_order(&[], &config),
+            [Mirror::Ncbi, Mirror::Ena]
+        );
+        let arguments = vec!["ddbj".to_owned()];
+        assert_eq!(
+            Mirror::resolution_order(&arguments, &config),
+            [Mirror::Ddbj, Mirror::Ena]
+        );
+    }
+}
@@ -1,74 +1,401 @@
-use std::sync::Arc;
-use regex::Regex;
+use serde::Deserialize;
 
-use crate::NetworkInstance;
+use crate::{GetReqError, NetworkInstance};
 
-/// This function does not spawn additional task.
+pub const EUTILS: &str = "https://eutils.ncbi.nlm.nih.gov/entrez/eutils";
+/// Number of records requested from efetch at once.
+const EFETCH_BATCH_SIZE: usize = 500;
+
+/// One row of the SRA RunInfo table, holding the fields we make use of.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RunInfo {
+    #[serde(rename = "Run")]
+    pub run: String,
+    #[serde(rename = "Experiment")]
+    pub experiment: String,
+    #[serde(rename = "SampleName", default)]
+    pub sample_name: String,
+    #[serde(rename = "LibraryName", default)]
+    pub library_name: String,
+    #[serde(rename = "SRAStudy", default)]
+    pub study: String,
+    #[serde(rename = "BioProject", default)]
+    pub bio_project: String,
+    #[serde(rename = "BioSample", default)]
+    pub bio_sample: String,
+    #[serde(rename = "Submission", default)]
+    pub submission: String,
+    /// `public`, or the consent group of a run protected by dbGaP.
+    #[serde(rename = "Consent", default)]
+    pub consent: String,
+    #[serde(rename = "spots", default)]
+    pub spots: Option<u64>,
+    #[serde(rename = "bases", default)]
+    pub bases: Option<u64>,
+    /// `PAIRED` or `SINGLE`.
+    #[serde(rename = "LibraryLayout", default)]
+    pub library_layout: String,
+}
+impl RunInfo {
+    /// Whether the run is, or belongs to, `accession`.
+    pub fn belongs_to(&self, accession: &str) -> bool {
+        [
+            &self.run,
+            &self.experiment,
+            &self.study,
+            &self.bio_project,
+            &self.bio_sample,
+        ]
+        .into_iter()
+        .any(|field| field == accession)
+    }
+    /// Spots and bases the archive reports for the run, when both are known.
+    pub fn read_counts(&self) -> Option<ReadCounts> {
+        Some(ReadCounts {
+            spots: self.spots?,
+            bases: self.bases?,
+        })
+    }
+    /// Whether the spots of the run hold two mates, `None` when the layout is not reported.
+    pub fn is_paired(&self) -> Option<bool> {
+        layout_is_paired(&self.library_layout)
+    }
+    /// Whether the run is protected by dbGaP and cannot be downloaded anonymously.
+    pub fn is_controlled(&self) -> bool {
+        !self.consent.is_empty() && !self.consent.eq_ignore_ascii_case("public")
+    }
+}
+
+/// Size of a run as reported by the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadCounts {
+    pub spots: u64,
+    pub bases: u64,
+}
+impl ReadCounts {
+    /// Whether `reads` reads with `bases` bases in total make up the whole run.
+    /// Paired runs may come with one read per mate.
+    pub fn matches(&self, reads: u64, bases: u64) -> bool {
+        bases == self.bases && (reads == self.spots || reads == 2 * self.spots)
+    }
+}
+
+/// Read a library layout as reported by SRA or ENA.
+pub fn layout_is_paired(layout: &str) -> Option<bool> {
+    match layout.trim().to_ascii_uppercase().as_str() {
+        "PAIRED" => Some(true),
+        "SINGLE" => Some(false),
+        _ => None,
+    }
+}
+
+/// A run to download, with the name its files are saved under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedRun {
+    pub name: String,
+    pub srr: String,
+    /// Checked against the downloaded files when known.
+    pub counts: Option<ReadCounts>,
+    /// Whether the run is paired-end, `None` when not known.
+    pub paired: Option<bool>,
+}
+
+#[derive(Debug)]
+pub enum ReadMetaError {
+    Request(GetReqError),
+    /// Nothing is known to NCBI under the accession.
+    NotFound(String),
+    /// The response could not be parsed.
+    Malformed(String),
+    /// The record lacks a field needed to name the file.
+    MissingField {
+        accession: String,
+        field: &'static str,
+    },
+    /// The data is under controlled access, e.g. on dbGaP or GSA-Human.
+    ControlledAccess(String),
+}
+impl ReadMetaError {
+    /// Whether the metadata puts the accession under controlled access,
+    /// so asking again or elsewhere is pointless.
+    /// A server denying a request is not taken as such, it may be a passing
+    /// or regional restriction of that server.
+    pub fn is_controlled_access(&self) -> bool {
+        matches!(self, Self::ControlledAccess(_))
+    }
+}
+impl From<GetReqError> for ReadMetaError {
+    fn from(value: GetReqError) -> Self {
+        Self::Request(value)
+    }
+}
+impl std::fmt::Display for ReadMetaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "Failed to read metadata: {}", e),
+            Self::NotFound(accession) => write!(f, "No runs found for {}", accession),
+            Self::Malformed(reason) => write!(f, "Malformed metadata: {}", reason),
+            Self::MissingField { accession, field } => {
+                write!(f, "{} has no {} in its metadata", accession, field)
+            }
+            Self::ControlledAccess(accession) => write!(
+                f,
+                "{} is under controlled access and cannot be downloaded anonymously",
+                accession
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ESearchResponse {
+    esearchresult: ESearchResult,
+}
+#[derive(Deserialize)]
+pub struct ESearchResult {
+    pub count: String,
+    #[serde(default)]
+    pub idlist: Vec<String>,
+    pub webenv: String,
+    pub querykey: String,
+}
+
+/// Search `db` for `term`, keeping the result on the history server.
 /// This function will retry network requests.
-pub async fn read_name_and_srr(
-    client: NetworkInstance,
-    srx_number: usize,
+pub async fn esearch(
+    client: &NetworkInstance,
+    db: &str,
+    term: &str,
     config: &crate::Config,
-    arguments: &Vec<String>,
-) -> Result<(String, String), ()> {
-    let result = crate::with_retry!(
-        config.retry_times =>
-            client.get_by_str(format!("https://www.ncbi.nlm.nih.gov/sra/SRX{}[accn]",srx_number),config.read_meta_timeout)
-    );
-    if let Err(_) = result {
-        return Err(());
-    }
-    let bytes = result.unwrap();
-    let document = String::from_utf8_lossy(&bytes[31220..(bytes.len()-18300)]).to_string();
-    drop(bytes);
-    let srr_pos = document
-        .find("//trace.ncbi.nlm.nih.gov/Traces?run=SRR")
-        .unwrap();
-    let mut srr_number = document[(srr_pos+35)..(srr_pos+48)].to_owned();
-    srr_number.retain(|char| char.is_alphanumeric());
-    let search_regex = Regex::new(">[^<>]*<").unwrap();
-    let filename = if arguments.contains(&"srr_override".into()) {
-        srr_number.clone()
-    } else if arguments.contains(&"library_name_override".into()) {
-        let pos = document
-            .find("Library: <div class=\"expand-body\"><div>Name: <span>")
-            .unwrap();
-        let name_search_result = search_regex.find(&document[pos + 48..pos + 75]);
-        if let Some(matched) = name_search_result {
-            let matched_str = matched.as_str();
-            matched_str[1..matched_str.len() - 1].to_owned()
-        } else {
-            panic!(
-                "No match for name under library section found on SRR{} with document {}",
-                srr_number,
-                &document[pos + 48..pos + 75]
-            )
+) -> Result<ESearchResult, ReadMetaError> {
+    let bytes = crate::with_retry!(
+        config.into() =>
+            client.get_by_str(format!(
+                "{}/esearch.fcgi?db={}&term={}&usehistory=y&retmode=json",
+                EUTILS, db, term
+            ), config.read_meta_timeout)
+    )?;
+    Ok(serde_json::from_slice::<ESearchResponse>(&bytes)
+        .map_err(|e| ReadMetaError::Malformed(format!("esearch for {}: {}", term, e)))?
+        .esearchresult)
+}
+
+/// Read the RunInfo rows of every run matching `term` in the SRA database.
+/// `term` may be any accession of a run or of something holding runs.
+/// The search is kept on the history server and fetched in batches.
+/// This function will retry network requests.
+pub async fn fetch_run_info(
+    client: &NetworkInstance,
+    term: &str,
+    config: &crate::Config,
+) -> Result<Vec<RunInfo>, ReadMetaError> {
+    let search = esearch(client, "sra", term, config).await?;
+    let count: usize = search
+        .count
+        .parse()
+        .map_err(|_| ReadMetaError::Malformed(format!("esearch count {}", search.count)))?;
+    if count == 0 {
+        return Err(ReadMetaError::NotFound(term.to_owned()));
+    }
+    let mut runs = Vec::new();
+    for retstart in (0..count).step_by(EFETCH_BATCH_SIZE) {
+        let bytes = crate::with_retry!(
+            config.into() =>
+                client.get_by_str(format!(
+                    "{}/efetch.fcgi?db=sra&rettype=runinfo&retmode=text&WebEnv={}&query_key={}&retstart={}&retmax={}",
+                    EUTILS, search.webenv, search.querykey, retstart, EFETCH_BATCH_SIZE
+                ), config.read_meta_timeout)
+        )?;
+        runs.extend(parse_run_info(&bytes, term)?);
+    }
+    if runs.is_empty() {
+        return Err(ReadMetaError::NotFound(term.to_owned()));
+    }
+    Ok(runs)
+}
+
+/// Read the rows of a RunInfo table fetched for `term`.
+fn parse_run_info(bytes: &[u8], term: &str) -> Result<Vec<RunInfo>, ReadMetaError> {
+    let malformed =
+        |e: csv::Error| ReadMetaError::Malformed(format!("runinfo for {}: {}", term, e));
+    let mut reader = csv::Reader::from_reader(bytes);
+    let headers = reader.headers().map_err(malformed)?.clone();
+    let mut runs = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(malformed)?;
+        // Batches may repeat the header line, which does not deserialize into the numeric fields.
+        if matches!(record.get(0), Some("Run") | Some("") | None) {
+            continue;
         }
+        runs.push(record.deserialize(Some(&headers)).map_err(malformed)?);
+    }
+    Ok(runs)
+}
+
+/// Choose the name of the downloaded file of a run according to the line arguments.
+/// Defaults to the sample name.
+pub fn run_file_name(run: &RunInfo, arguments: &[String]) -> Result<String, ReadMetaError> {
+    let (name, field) = if arguments.contains(&"srr_override".into()) {
+        (&run.run, "run accession")
+    } else if arguments.contains(&"library_name_override".into()) {
+        (&run.library_name, "library name")
     } else {
-        let pos = document.find("Sample: <span>").unwrap();
-        let matched = search_regex.find(&document[(pos+10)..(pos + 100)]).unwrap().as_str();
-        matched[1..(matched.len()-1)].to_owned()
+        (&run.sample_name, "sample name")
     };
-    Ok((filename, srr_number))
+    if name.trim().is_empty() {
+        return Err(ReadMetaError::MissingField {
+            accession: run.run.clone(),
+            field,
+        });
+    }
+    Ok(name.trim().to_owned())
 }
 
-enum ReadSrrError {
-    RetryTimesReached,
-    NotFound,
+/// Resolve every run of an experiment, study, BioProject or BioSample
+/// together with the name of its file.
+/// When several runs end up with the same name, the run accession is appended
+/// to it so that the files do not overwrite each other.
+/// This function does not spawn additional task.
+/// This function will retry network requests.
+pub async fn read_names_and_srrs(
+    client: NetworkInstance,
+    accession: &str,
+    config: &crate::Config,
+    arguments: &[String],
+) -> Result<Vec<NamedRun>, ReadMetaError> {
+    let runs = fetch_run_info(&client, accession, config)
+        .await?
+        .into_iter()
+        // The search is full text, keep only the runs that really belong to the accession.
+        .filter(|run| run.belongs_to(accession))
+        .collect::<Vec<_>>();
+    let runs = public_runs(accession, runs)?;
+    let runs = runs
+        .iter()
+        .map(|run| {
+            Ok(NamedRun {
+                name: run_file_name(run, arguments)?,
+                srr: run.run.clone(),
+                counts: run.read_counts(),
+                paired: run.is_paired(),
+            })
+        })
+        .collect::<Result<Vec<_>, ReadMetaError>>()?;
+    Ok(disambiguate(runs))
 }
 
-pub async fn preflight_srx(
-    client: NetworkInstance,
-    srx: usize,
-    config: Arc<crate::Config>,
-    arguments: Arc<Vec<String>>,
-) -> Result<(String, String), ()> {
-    let result = crate::with_retry!(
-        config.retry_times =>
-        read_name_and_srr(client.clone(), srx, config.as_ref(), arguments.as_ref())
-    );
-    if let Ok(v) = &result{
-        println!("Found {} with {}",v.0, v.1);
-    }
-    result
+/// Drop the runs protected by dbGaP, telling which ones are skipped.
+/// Fails when nothing else is left.
+pub fn public_runs(accession: &str, runs: Vec<RunInfo>) -> Result<Vec<RunInfo>, ReadMetaError> {
+    if runs.is_empty() {
+        return Err(ReadMetaError::NotFound(accession.to_owned()));
+    }
+    let (controlled, public): (Vec<_>, Vec<_>) = runs.into_iter().partition(RunInfo::is_controlled);
+    for run in controlled.iter() {
+        crate::progress!(
+            "Skipping {}: controlled access on dbGaP, consent {}",
+            run.run,
+            run.consent
+        );
+    }
+    if public.is_empty() {
+        return Err(ReadMetaError::ControlledAccess(accession.to_owned()));
+    }
+    Ok(public)
+}
+
+/// Append the run accession to every name that is shared by several runs.
+pub fn disambiguate(runs: Vec<NamedRun>) -> Vec<NamedRun> {
+    let names = runs.iter().map(|run| run.name.clone()).collect::<Vec<_>>();
+    runs.into_iter()
+        .map(|mut run| {
+            if names.iter().filter(|name| **name == run.name).count() > 1 {
+                run.name = format!("{}_{}", run.name, run.srr);
+            }
+            run
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str, srr: &str) -> NamedRun {
+        NamedRun {
+            name: name.to_owned(),
+            srr: srr.to_owned(),
+            counts: None,
+            paired: None,
+        }
+    }
+
+    #[test]
+    fn shared_names_get_their_run_appended() {
+        // Two runs of one experiment and a third of another experiment of the same sample.
+        let line = disambiguate(vec![run("liver", "SRR1"), run("liver", "SRR2")])
+            .into_iter()
+            .chain([run("liver", "SRR3"), run("heart", "SRR4")])
+            .collect();
+        let names = disambiguate(line)
+            .into_iter()
+            .map(|run| run.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["liver_SRR1", "liver_SRR2", "liver", "heart"]);
+        let names = disambiguate(vec![run("liver", "SRR1"), run("liver", "SRR3")])
+            .into_iter()
+            .map(|run| run.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["liver_SRR1", "liver_SRR3"]);
+    }
+
+    #[test]
+    fn read_counts_match_single_and_paired_files() {
+        let counts = ReadCounts {
+            spots: 10,
+            bases: 1500,
+        };
+        assert!(counts.matches(10, 1500));
+        // One read per mate of a paired run
+        assert!(counts.matches(20, 1500));
+        assert!(!counts.matches(10, 1499));
+        assert!(!counts.matches(15, 1500));
+        assert!(!counts.matches(0, 0));
+    }
+
+    #[test]
+    fn only_metadata_puts_runs_under_controlled_access() {
+        let denied = GetReqError::Forbidden(reqwest::StatusCode::FORBIDDEN);
+        assert!(!ReadMetaError::Request(denied).is_controlled_access());
+        assert!(ReadMetaError::ControlledAccess("SRR1".to_owned()).is_controlled_access());
+        let run = |consent: &str| RunInfo {
+            consent: consent.to_owned(),
+            ..parse_run_info(b"Run,Experiment\nSRR1,SRX1\n", "SRR1")
+                .unwrap()
+                .remove(0)
+        };
+        assert!(!run("public").is_controlled());
+        assert!(!run("").is_controlled());
+        assert!(run("GRU-NPU").is_controlled());
+    }
+
+    #[test]
+    fn skips_repeated_header_lines() {
+        let table = "Run,Experiment,spots,bases,LibraryLayout\n\
+                     SRR1,SRX1,10,1500,PAIRED\n\
+                     Run,Experiment,spots,bases,LibraryLayout\n\
+                     SRR2,SRX1,,,SINGLE\n";
+        let runs = parse_run_info(table.as_bytes(), "SRX1").unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(
+            runs[0].read_counts(),
+            Some(ReadCounts {
+                spots: 10,
+                bases: 1500
+            })
+        );
+        assert_eq!(runs[0].is_paired(), Some(true));
+        assert_eq!(runs[1].run, "SRR2");
+        assert_eq!(runs[1].read_counts(), None);
+    }
 }
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+
+/// Pause applied to a host that answered 429 without saying for how long.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+/// A throttled host is never slowed down below this many requests per second.
+const MIN_RATE: f64 = 0.1;
+
+/// Token bucket per host.
+/// Every request to a host takes a token, tokens are refilled at the configured
+/// requests per second and up to one second worth of them can be saved up.
+/// A host that answers 429 has its rate halved, which then recovers
+/// a little with every request that is not throttled.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    rates: HashMap<String, f64>,
+    default_rate: f64,
+}
+impl RateLimiter {
+    /// `rates` holds requests per second by host name,
+    /// hosts not listed there get `default_rate`.
+    pub fn new(rates: HashMap<String, f64>, default_rate: f64) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            rates,
+            default_rate,
+        }
+    }
+    /// Wait until a request to `host` is allowed.
+    pub async fn acquire(&self, host: &str) {
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().expect("lock not to be poisoned");
+                let bucket = buckets
+                    .entry(host.to_owned())
+                    .or_insert_with(|| Bucket::new(self.rate_of(host)));
+                match bucket.try_take(Instant::now()) {
+                    None => return,
+                    Some(wait) => wait,
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+    /// Adjust the rate of `host` to what the server told us.
+    /// Slows down on 429 and pauses for as long as `Retry-After` asks.
+    pub fn observe(&self, host: &str, response: &Response) {
+        let throttled = response.status() == StatusCode::TOO_MANY_REQUESTS;
+        let retry_after = retry_after(response);
+        let mut buckets = self.buckets.lock().expect("lock not to be poisoned");
+        let bucket = buckets
+            .entry(host.to_owned())
+            .or_insert_with(|| Bucket::new(self.rate_of(host)));
+        let now = Instant::now();
+        if throttled {
+            bucket.rate = (bucket.rate / 2.0).max(MIN_RATE);
+            bucket.tokens = 0.0;
+            crate::progress!(
+                "{} is throttling requests, slowing down to {:.2} requests per second",
+                host,
+                bucket.rate
+            );
+        } else {
+            bucket.rate = (bucket.rate + bucket.max_rate / 10.0).min(bucket.max_rate);
+        }
+        if let Some(pause) = retry_after.or(throttled.then_some(DEFAULT_BACKOFF)) {
+            bucket.paused_until = Some(bucket.paused_until.unwrap_or(now).max(now + pause));
+        }
+    }
+    fn rate_of(&self, host: &str) -> f64 {
+        self.rates.get(host).copied().unwrap_or(self.default_rate)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Rate currently in effect, lowered while the host is throttling us.
+    rate: f64,
+    /// Configured rate.
+    max_rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    paused_until: Option<Instant>,
+}
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        let rate = rate.max(MIN_RATE);
+        Self {
+            rate,
+            max_rate: rate,
+            tokens: rate.max(1.0),
+            last_refill: Instant::now(),
+            paused_until: None,
+        }
+    }
+    /// Take a token, or return how long to wait for the next one.
+    fn try_take(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(paused_until) = self.paused_until {
+            if paused_until > now {
+                return Some(paused_until - now);
+            }
+            self.paused_until = None;
+        }
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Read the `Retry-After` header, given either in seconds or as a date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode, retry_after: Option<&str>) -> Response {
+        let mut builder = http::Response::builder().status(status);
+        if let Some(value) = retry_after {
+            builder = builder.header(RETRY_AFTER, value);
+        }
+        builder.body("").unwrap().into()
+    }
+
+    #[test]
+    fn tokens_refill_at_rate() {
+        let mut bucket = Bucket::new(2.0);
+        let start = bucket.last_refill;
+        // Up to one second worth of tokens is saved up.
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), None);
+        let wait = bucket.try_take(start).unwrap();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-9);
+        assert_eq!(bucket.try_take(start + Duration::from_millis(500)), None);
+        // Idle time does not save up more than the cap.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.try_take(later), None);
+        assert_eq!(bucket.try_take(later), None);
+        assert!(bucket.try_take(later).is_some());
+    }
+
+    #[test]
+    fn throttled_host_pauses_and_slows_down() {
+        let limiter = RateLimiter::new(HashMap::from([("a".to_owned(), 4.0)]), 1.0);
+        limiter.observe("a", &response(StatusCode::TOO_MANY_REQUESTS, Some("30")));
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut("a").unwrap();
+        assert_eq!(bucket.rate, 2.0);
+        let wait = bucket.try_take(Instant::now()).unwrap();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        drop(buckets);
+        // Without Retry-After the default pause applies, and the rate keeps halving.
+        limiter.observe("b", &response(StatusCode::TOO_MANY_REQUESTS, None));
+        limiter.observe("b", &response(StatusCode::TOO_MANY_REQUESTS, None));
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut("b").unwrap();
+        assert_eq!(bucket.rate, 0.25);
+        assert!(bucket.try_take(Instant::now()).unwrap() <= DEFAULT_BACKOFF);
+        drop(buckets);
+        // Successful responses recover the rate a tenth of the configured one at a time.
+        limiter.observe("a", &response(StatusCode::OK, None));
+        assert_eq!(limiter.buckets.lock().unwrap()["a"].rate, 2.4);
+    }
+
+    #[test]
+    fn reads_retry_after_in_seconds_and_as_date() {
+        let after = |value| retry_after(&response(StatusCode::TOO_MANY_REQUESTS, Some(value)));
+        assert_eq!(after("120"), Some(Duration::from_secs(120)));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = after(&date).unwrap();
+        assert!(wait > Duration::from_secs(58) && wait <= Duration::from_secs(60));
+        // A date in the past asks for no pause.
+        assert_eq!(after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+        assert_eq!(after("soon"), None);
+        assert_eq!(retry_after(&response(StatusCode::OK, None)), None);
+    }
+}
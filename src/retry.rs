@@ -0,0 +1,111 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+
+use crate::{Config, GetReqError};
+
+/// How `with_retry!` spaces out its attempts.
+/// The delay doubles after every failed attempt up to `max_delay`,
+/// and a random part of it is dropped so that parallel tasks spread out.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+impl RetryPolicy {
+    /// Delay before retrying after `attempt` failed attempts, starting from 1.
+    /// Falls between half of and the full exponential delay.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31) as u32;
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+impl From<&Config> for RetryPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_retries: config.retry_times,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+        }
+    }
+}
+
+/// A random number in `[0, 1)`.
+fn jitter() -> f64 {
+    // `RandomState` is seeded randomly for every instance, which is enough for jitter.
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Errors that may go away when the same request is sent again.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+impl Retryable for GetReqError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Timeout | Self::TooManyRequests | Self::ServerError(_) => true,
+            Self::ClientError(status) => *status == StatusCode::REQUEST_TIMEOUT,
+            // Usually a maintenance page served in place of the file.
+            Self::UnexpectedContentType(_) => true,
+            // The file was damaged on the way and has been removed.
+            Self::ChecksumMismatch { .. } | Self::LengthMismatch { .. } => true,
+            Self::NotFound | Self::Forbidden(_) | Self::UnexpectedStatus(_) => false,
+            // Local file system errors do not go away on their own.
+            Self::Io(_) => false,
+            Self::Other(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        for (attempt, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (64, 1000),
+        ] {
+            let full = Duration::from_millis(full);
+            for _ in 0..20 {
+                let delay = policy.delay(attempt);
+                assert!(
+                    full / 2 <= delay && delay <= full,
+                    "attempt {} waited {:?}",
+                    attempt,
+                    delay
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        assert!(GetReqError::Timeout.is_retryable());
+        assert!(GetReqError::ServerError(StatusCode::BAD_GATEWAY).is_retryable());
+        assert!(GetReqError::ClientError(StatusCode::REQUEST_TIMEOUT).is_retryable());
+        assert!(!GetReqError::ClientError(StatusCode::BAD_REQUEST).is_retryable());
+        assert!(!GetReqError::NotFound.is_retryable());
+    }
+}